@@ -0,0 +1,59 @@
+//
+// Decode timings for each subband level, on a frequency-ordered
+// copy of the HDR sample. Like the tests, this needs
+// samples/panel-hdr.jxr from a full checkout of the repository;
+// another file can be given instead.
+//
+// cargo bench --bench decode [-- path/to/file.jxr]
+//
+
+use std::fs::File;
+use std::io::{BufReader, Cursor};
+use std::time::{Duration, Instant};
+
+use jpegxr::{BitstreamFormat, DecodeOptions, EncoderOptions, ImageDecode, ImageEncode, PixelInfo, Subband};
+
+const RUNS: usize = 5;
+
+fn main() {
+    let path = std::env::args().skip(1).find(|arg| !arg.starts_with("--"))
+        .unwrap_or_else(|| "samples/panel-hdr.jxr".to_string());
+    let file = File::open(&path).unwrap_or_else(|e| {
+        panic!("{}: {}; check out the samples directory or pass a file", path, e)
+    });
+    let mut decoder = ImageDecode::with_reader(BufReader::new(file)).unwrap();
+    let format = decoder.get_pixel_format().unwrap();
+    let (width, height) = decoder.get_size().unwrap();
+    let stride = width as usize * PixelInfo::from_format(format).bits_per_pixel() / 8;
+    let mut pixels = vec![0u8; stride * height as usize];
+    decoder.copy_all(&mut pixels, stride).unwrap();
+
+    let mut encoder = ImageEncode::with_writer(Cursor::new(Vec::new())).unwrap();
+    encoder.set_pixel_format(format).unwrap();
+    encoder.set_size(width, height).unwrap();
+    encoder.set_options(&EncoderOptions {
+        bitstream: BitstreamFormat::Frequency,
+        ..EncoderOptions::with_quality(0.9)
+    });
+    encoder.write_pixels(&pixels, stride).unwrap();
+    let bytes = encoder.into_writer().into_inner();
+    println!("{}: {}x{} {:?}, {} bytes frequency-ordered", path, width, height, format, bytes.len());
+
+    let mut decoder = ImageDecode::with_reader(Cursor::new(bytes)).unwrap();
+    let mut output = vec![0u8; pixels.len()];
+    let mut full = Duration::default();
+    for &subband in &[Subband::All, Subband::NoFlexbits, Subband::NoHighpass, Subband::DcOnly] {
+        decoder.set_options(&DecodeOptions { subband, ..Default::default() }).unwrap();
+        let mut best = Duration::MAX;
+        for _ in 0..RUNS {
+            let start = Instant::now();
+            decoder.copy_all(&mut output, stride).unwrap();
+            best = best.min(start.elapsed());
+        }
+        if subband == Subband::All {
+            full = best;
+        }
+        println!("{:<12} {:>8.1} ms  {:>5.2}x", format!("{:?}", subband),
+            best.as_secs_f64() * 1000.0, full.as_secs_f64() / best.as_secs_f64());
+    }
+}
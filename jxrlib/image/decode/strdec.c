@@ -2605,12 +2605,13 @@ Int initLookupTables(CWMImageStrCodec* pSC)
     switch(pII->cfColorFormat){
         case YUV_420:
             cStrideX = 6;
-            w >>= 1, h >>= 1;
+            // odd sizes still have a (padded) unit for the last column and row
+            w = (w + 1) >> 1, h = (h + 1) >> 1;
             break;
 
         case YUV_422:
             cStrideX = 4;
-            w >>= 1;
+            w = (w + 1) >> 1;
             break;
 
         default:
@@ -2668,6 +2669,11 @@ Void setROI(CWMImageStrCodec* pSC)
     pWMII->cWidth -= pSC->m_param.cExtraPixelsLeft + pSC->m_param.cExtraPixelsRight;
     pWMII->cHeight -= pSC->m_param.cExtraPixelsTop + pSC->m_param.cExtraPixelsBottom;
 
+    // bands are in separate packets in frequency mode, so the caller may
+    // decode fewer than were stored; the packets are already laid out by now
+    if(pSCP->bfBitstreamFormat == FREQUENCY && pWMII->sbSubband > pSCP->sbSubband && pWMII->sbSubband < SB_ISOLATED)
+        pSCP->sbSubband = pWMII->sbSubband;
+
     pSC->m_Dparam->bSkipFlexbits = (pSCP->sbSubband == SB_NO_FLEXBITS);
     pSC->m_Dparam->bDecodeHP = (pSCP->sbSubband == SB_ALL || pSCP->sbSubband == SB_NO_FLEXBITS);
     pSC->m_Dparam->bDecodeLP = (pSCP->sbSubband != SB_DC_ONLY);
@@ -2809,6 +2815,8 @@ Int StrDecTerm(CWMImageStrCodec* pSC)
 
         FreeCodingContextDec(pSC);
 
+        termPostProc(pSC->pPostProcInfo, pSC->m_param.cNumChannels);
+
         if (j == 0) {
             StrIODecTerm(pSC);
 
@@ -32,11 +32,14 @@
 //================================
 #define PACKETLENGTH (1U<<12)   // 4kB
 
+// L1 is once per macroblock, L2 once per block. A macroblock of
+// lossless 16-bit data with many channels can outgrow a packet, so
+// the buffers have to be serviced per block too.
 #define readIS_L1(pSC, pIO) readIS(pSC, pIO)
-#define readIS_L2(pSC, pIO) (void)(pSC, pIO)
+#define readIS_L2(pSC, pIO) readIS(pSC, pIO)
 
 #define writeIS_L1(pSC, pIO) writeIS(pSC, pIO)
-#define writeIS_L2(pSC, pIO) (void)(pSC, pIO)
+#define writeIS_L2(pSC, pIO) writeIS(pSC, pIO)
 
 
 //================================
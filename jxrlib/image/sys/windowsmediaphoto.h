@@ -345,6 +345,9 @@ typedef struct tagCWMImageInfo {
     size_t cThumbnailWidth;
     size_t cThumbnailHeight;
 
+    // subbands to decode, frequency mode only
+    SUBBAND sbSubband;
+
     // image orientation
     ORIENTATION oOrientation;
 
@@ -35,11 +35,12 @@
 //================================
 #define PACKETLENGTH (1U<<12)   // 4kB
 
+// See ansi.h
 #define readIS_L1(pSC, pIO) readIS(pSC, pIO)
-#define readIS_L2(pSC, pIO) (void)(pSC, pIO)
+#define readIS_L2(pSC, pIO) readIS(pSC, pIO)
 
 #define writeIS_L1(pSC, pIO) writeIS(pSC, pIO)
-#define writeIS_L2(pSC, pIO) (void)(pSC, pIO)
+#define writeIS_L2(pSC, pIO) writeIS(pSC, pIO)
 
 
 //================================
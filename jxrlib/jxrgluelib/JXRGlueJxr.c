@@ -32,6 +32,16 @@
 
 
 static const char szHDPhotoFormat[] = "<dc:format>image/vnd.ms-photo</dc:format>";
+
+// Captions are UTF-16, but wchar_t is 32 bits outside Windows.
+static size_t StrLenU16(const U16 *pwsz)
+{
+    size_t cch = 0;
+    while (pwsz[cch] != 0)
+        cch++;
+    return cch;
+}
+
 const U32 IFDEntryTypeSizes[] = { 0, 1, 1, 2, 4, 8, 1, 1, 2, 4, 8, 4, 8 };
 const U32 SizeofIFDEntry = sizeof(struct IFDEntry);
 
@@ -64,7 +74,7 @@ void CalcMetadataSizeLPWSTR(const DPKPROPVARIANT var,
 {
     if (DPKVT_EMPTY != var.vt)
     {
-        U32 uiCBWithNull = sizeof(U16) * ((U32)wcslen((wchar_t *) var.VT.pwszVal) + 1); // +1 for NULL term;
+        U32 uiCBWithNull = sizeof(U16) * ((U32)StrLenU16(var.VT.pwszVal) + 1); // +1 for NULL term;
         assert(DPKVT_LPWSTR == var.vt);
 
         // We only use offset if size > 4
@@ -149,7 +159,7 @@ ERR CopyDescMetadata(DPKPROPVARIANT *pvarDst,
             
         case DPKVT_LPWSTR:
             pvarDst->vt = DPKVT_LPWSTR;
-            uiSize = sizeof(U16) * (wcslen((wchar_t *) varSrc.VT.pwszVal) + 1); // +1 for NULL term
+            uiSize = sizeof(U16) * (StrLenU16(varSrc.VT.pwszVal) + 1); // +1 for NULL term
             Call(PKAlloc((void **) &pvarDst->VT.pszVal, uiSize));
             memcpy(pvarDst->VT.pwszVal, varSrc.VT.pwszVal, uiSize);
             break;
@@ -217,7 +227,9 @@ ERR WriteDescMetadata(PKImageEncode *pIE,
     U32 uiDataWrittenToOffset = 0;
     U16 uiTemp = 0;
 
-    if (0 == pDEMisc->uDescMetadataOffset || 0 == pDEMisc->uDescMetadataByteCount)
+    // Values of 4 bytes or less live in the entry itself, so there may be
+    // active entries even when no offset space was reserved.
+    if (DPKVT_EMPTY == var.vt)
         goto Cleanup; // Nothing to do here
 
     // Sanity check before - can be equal due to remaining metadata being DPKVT_EMPTY
@@ -251,7 +263,8 @@ ERR WriteDescMetadata(PKImageEncode *pIE,
 
         case DPKVT_UI4:
             CalcMetadataSizeUI4(var, &uiTemp, &uiMetadataOffsetSize);
-            pwmpDE->uCount = 1;
+            // The only UI4 is PageNumber, which is two SHORTs.
+            pwmpDE->uCount = 2;
             pwmpDE->uValueOrOffset = var.VT.ulVal;
             Call(WriteWmpDE(pWS, poffPos, pwmpDE, NULL, NULL));
             break;
@@ -285,6 +298,7 @@ ERR WriteContainerPre(
     WmpDEMisc* pDEMisc = &pIE->WMP.wmiDEMisc;
     PKPixelInfo PI;
     size_t offPos = 0;
+    size_t offPFD = OFFSET_OF_PFD;
 
     U8 IIMM[2] = {'\x49', '\x49'};
     // const U32 cbWmpDEMisc = OFFSET_OF_PFD;
@@ -340,13 +354,24 @@ ERR WriteContainerPre(
 
     //================
     Call(pWS->GetPos(pWS, &offPos));
-    FailIf(0 != offPos, WMP_errUnsupportedFormat);
-
-    //================
-    // Header (8 bytes)
-    Call(pWS->Write(pWS, IIMM, sizeof(IIMM))); offPos += 2;
-    Call(PutUShort(pWS, offPos, 0x01bc)); offPos += 2;
-    Call(PutULong(pWS, offPos, (U32)OFFSET_OF_PFD)); offPos += 4;
+    if (0 == offPos)
+    {
+        //================
+        // Header (8 bytes)
+        Call(pWS->Write(pWS, IIMM, sizeof(IIMM))); offPos += 2;
+        Call(PutUShort(pWS, offPos, 0x01bc)); offPos += 2;
+        Call(PutULong(pWS, offPos, (U32)OFFSET_OF_PFD)); offPos += 4;
+    }
+    else
+    {
+        //================
+        // Later frame: its pixel format and PFD follow the previous
+        // frame's data, and are linked from the previous PFD
+        FailIf(0 == pDEMisc->uOffNextIFD || 0 != (offPos & 1), WMP_errOutOfSequence);
+        offPFD = offPos + 16;
+        Call(PutULong(pWS, pDEMisc->uOffNextIFD, (U32)offPFD));
+        Call(pWS->SetPos(pWS, offPos));
+    }
 
     //================
     // Write overflow area
@@ -372,10 +397,10 @@ ERR WriteContainerPre(
 
     //================
     // PFD
-    assert (offPos <= OFFSET_OF_PFD); // otherwise stuff is overwritten
-    if (offPos < OFFSET_OF_PFD)
-        Call(pWS->Write(pWS, Zero, OFFSET_OF_PFD - offPos));
-    offPos = (size_t)OFFSET_OF_PFD;
+    assert (offPos <= offPFD); // otherwise stuff is overwritten
+    if (offPos < offPFD)
+        Call(pWS->Write(pWS, Zero, offPFD - offPos));
+    offPos = offPFD;
 
     if (!pIE->WMP.bHasAlpha || pIE->WMP.wmiSCP.uAlphaMode != 2) //no planar alpha
         cWmpDEs -= 2;
@@ -669,6 +694,7 @@ ERR WriteContainerPre(
     }
 
     //================
+    pDEMisc->uOffNextIFD = (U32)offPos;
     Call(PutULong(pWS, offPos, 0)); offPos += 4;
 
     assert(0 == (offPos & 1));
@@ -1351,6 +1377,7 @@ ERR PKImageEncode_WritePixelsBandedEnd_WMP(PKImageEncode* pIE)
     }
 
     Call(WriteContainerPost(pIE));
+    pIE->WMP.eBandedEncState = BANDEDENCSTATE_TERMINATED;
 
 Cleanup:
     return err;
@@ -1435,31 +1462,9 @@ Cleanup:
     return err;
 }
 
-ERR PKImageEncode_CreateNewFrame_WMP(
-    PKImageEncode* pIE,
-    void* pvParam,
-    size_t cbParam)
-{
-    ERR err = WMP_errSuccess;
-
-    UNREFERENCED_PARAMETER( pIE );
-    UNREFERENCED_PARAMETER( pvParam );
-    UNREFERENCED_PARAMETER( cbParam );
-
-    Call(WMP_errNotYetImplemented);
-    
-Cleanup:
-    return err;
-}
-
-ERR PKImageEncode_Release_WMP(
-    PKImageEncode** ppIE)
+static void FreeEncoderMetadata(
+    PKImageEncode* pIE)
 {
-    ERR err = WMP_errSuccess;
-
-    PKImageEncode *pIE = *ppIE;
-    pIE->pStream->Close(&pIE->pStream);
-
     PKFree((void **) &pIE->pbColorContext);
     pIE->cbColorContext = 0;
     PKFree((void **) &pIE->pbXMPMetadata);
@@ -1488,6 +1493,59 @@ ERR PKImageEncode_Release_WMP(
     FreeDescMetadata(&pIE->sDescMetadata.pvarPageName);
     FreeDescMetadata(&pIE->sDescMetadata.pvarPageNumber);
     FreeDescMetadata(&pIE->sDescMetadata.pvarHostComputer);
+}
+
+ERR PKImageEncode_CreateNewFrame_WMP(
+    PKImageEncode* pIE,
+    void* pvParam,
+    size_t cbParam)
+{
+    ERR err = WMP_errSuccess;
+    struct WMPStream* pWS = pIE->pStream;
+    U32 uOffNextIFD = pIE->WMP.wmiDEMisc.uOffNextIFD;
+    size_t offPos = 0;
+    const U8 Zero = 0;
+
+    // The previous frame must be complete
+    FailIf(!pIE->fHeaderDone, WMP_errOutOfSequence);
+    FailIf(BANDEDENCSTATE_NONBANDEDENCODE != pIE->WMP.eBandedEncState &&
+        BANDEDENCSTATE_TERMINATED != pIE->WMP.eBandedEncState, WMP_errOutOfSequence);
+
+    // The new frame starts after the previous one's data, word aligned
+    offPos = (size_t)(pIE->WMP.nOffImage + pIE->WMP.nCbImage);
+    if (pIE->WMP.bHasAlpha && pIE->WMP.wmiSCP.uAlphaMode == 2)
+        offPos = (size_t)(pIE->WMP.nOffAlpha + pIE->WMP.nCbAlpha);
+    Call(pWS->SetPos(pWS, offPos));
+    if (offPos & 1)
+        Call(pWS->Write(pWS, &Zero, 1));
+
+    // Size, pixel format and resolution carry over; the rest starts afresh
+    Call(PKImageEncode_Initialize_WMP(pIE, pWS, pvParam, cbParam));
+    FreeEncoderMetadata(pIE);
+    memset(&pIE->sDescMetadata, 0, sizeof(pIE->sDescMetadata));
+    memset(&pIE->WMP.wmiDEMisc, 0, sizeof(pIE->WMP.wmiDEMisc));
+    pIE->WMP.wmiDEMisc.uOffNextIFD = uOffNextIFD;
+    pIE->WMP.bHasAlpha = FALSE;
+    pIE->WMP.nOffImage = pIE->WMP.nCbImage = 0;
+    pIE->WMP.nOffAlpha = pIE->WMP.nCbAlpha = 0;
+    pIE->WMP.eBandedEncState = BANDEDENCSTATE_UNINITIALIZED;
+    pIE->WMP.pPATempFile = NULL;
+    pIE->fHeaderDone = FALSE;
+    pIE->cFrame++;
+
+Cleanup:
+    return err;
+}
+
+ERR PKImageEncode_Release_WMP(
+    PKImageEncode** ppIE)
+{
+    ERR err = WMP_errSuccess;
+
+    PKImageEncode *pIE = *ppIE;
+    pIE->pStream->Close(&pIE->pStream);
+
+    FreeEncoderMetadata(pIE);
 
     Call(PKFree((void **) ppIE));
 
@@ -1788,7 +1846,8 @@ Cleanup:
 }
 
 ERR ReadContainer(
-    PKImageDecode* pID)
+    PKImageDecode* pID,
+    U32 uFrame)
 {
     ERR err = WMP_errSuccess;
 
@@ -1798,6 +1857,7 @@ ERR ReadContainer(
     char szSig[2] = {0};
     U16 uWmpID = 0;
     U32 offPFD = 0;
+    U32 offNextPFD = 0;
     U16 cPFDEntry = 0;
     U8 bVersion;
     
@@ -1817,7 +1877,30 @@ ERR ReadContainer(
     bVersion = (0xFF00 & uWmpID) >> 8;
     FailIf(bVersion != 0 && bVersion != 1, WMP_errUnsupportedFormat);
 
-    Call(GetULong(pWS, offPos, &offPFD)); offPos += 4;
+    Call(GetULong(pWS, offPos, &offNextPFD)); offPos += 4;
+
+    //================================
+    // Frames: a chain of PFDs, each ending with the offset of the next
+    // (capped, in case of a loop). A broken link past the first
+    // frame just ends the chain.
+    for (pID->cFrame = 0; 0 != offNextPFD && pID->cFrame < USHRT_MAX; ++pID->cFrame)
+    {
+        offPos = (size_t)offNextPFD;
+        if (WMP_errSuccess != GetUShort(pWS, offPos, &cPFDEntry) ||
+            0 == cPFDEntry || USHRT_MAX == cPFDEntry)
+        {
+            FailIf(0 == pID->cFrame, WMP_errUnsupportedFormat);
+            break;
+        }
+        if (pID->cFrame == uFrame)
+            offPFD = offNextPFD;
+
+        offPos += 2 + SizeofIFDEntry * cPFDEntry;
+        if (WMP_errSuccess != GetULong(pWS, offPos, &offNextPFD))
+            offNextPFD = 0;
+    }
+    FailIf(0 == pID->cFrame, WMP_errUnsupportedFormat);
+    FailIf(uFrame >= pID->cFrame, WMP_errIndexNotFound);
 
     //================================
     // PFD
@@ -1835,9 +1918,10 @@ Cleanup:
 
 
 //================================================
-ERR PKImageDecode_Initialize_WMP(
+static ERR InitializeFrame_WMP(
     PKImageDecode* pID,
-    struct WMPStream* pWS)
+    struct WMPStream* pWS,
+    U32 uFrame)
 {
     ERR err = WMP_errSuccess;
 
@@ -1847,7 +1931,7 @@ ERR PKImageDecode_Initialize_WMP(
     Call(PKImageDecode_Initialize(pID, pWS));
 
     //================================
-    Call(ReadContainer(pID));
+    Call(ReadContainer(pID, uFrame));
 
     //================================
     pID->WMP.wmiSCP.pWStream = pWS;
@@ -1881,6 +1965,52 @@ Cleanup:
     return err;
 }
 
+ERR PKImageDecode_Initialize_WMP(
+    PKImageDecode* pID,
+    struct WMPStream* pWS)
+{
+    return InitializeFrame_WMP(pID, pWS, 0);
+}
+
+static void FreeDecoderMetadata(
+    PKImageDecode* pID)
+{
+    FreeDescMetadata(&pID->WMP.sDescMetadata.pvarImageDescription);
+    FreeDescMetadata(&pID->WMP.sDescMetadata.pvarCameraMake);
+    FreeDescMetadata(&pID->WMP.sDescMetadata.pvarCameraModel);
+    FreeDescMetadata(&pID->WMP.sDescMetadata.pvarSoftware);
+    FreeDescMetadata(&pID->WMP.sDescMetadata.pvarDateTime);
+    FreeDescMetadata(&pID->WMP.sDescMetadata.pvarArtist);
+    FreeDescMetadata(&pID->WMP.sDescMetadata.pvarCopyright);
+    FreeDescMetadata(&pID->WMP.sDescMetadata.pvarRatingStars);
+    FreeDescMetadata(&pID->WMP.sDescMetadata.pvarRatingValue);
+    FreeDescMetadata(&pID->WMP.sDescMetadata.pvarCaption);
+    FreeDescMetadata(&pID->WMP.sDescMetadata.pvarDocumentName);
+    FreeDescMetadata(&pID->WMP.sDescMetadata.pvarPageName);
+    FreeDescMetadata(&pID->WMP.sDescMetadata.pvarPageNumber);
+    FreeDescMetadata(&pID->WMP.sDescMetadata.pvarHostComputer);
+}
+
+ERR PKImageDecode_SelectFrame_WMP(
+    PKImageDecode* pID,
+    U32 uFrame)
+{
+    ERR err = WMP_errSuccess;
+    struct WMPStream* pWS = pID->pStream;
+
+    // Each frame is read from scratch, so switch before decoding
+    FailIf(0 != pID->WMP.DecoderCurrMBRow, WMP_errOutOfSequence);
+    FailIf(uFrame >= pID->cFrame, WMP_errIndexNotFound);
+
+    FreeDecoderMetadata(pID);
+    memset(&pID->WMP, 0, sizeof(pID->WMP));
+    Call(pWS->SetPos(pWS, pID->offStart));
+    Call(InitializeFrame_WMP(pID, pWS, uFrame));
+
+Cleanup:
+    return err;
+}
+
 
 ERR PKImageDecode_GetSize_WMP(
     PKImageDecode* pID,
@@ -1928,8 +2058,9 @@ ERR PKImageDecode_Copy_WMP(
     CWMImageBufferInfo wmiBI = { 0 };
 #ifdef REENTRANT_MODE
     U8 *pbLowMemAdj = NULL;
-    U32 i, cMBRow;
+    U32 i, cMBRow = 0;
     U32 cMBRowStart;
+    PKRect rcRotated;
 #endif // REENTRANT_MODE
     struct WMPStream* pWS = pID->pStream;
     U8 tempAlphaMode = 0;
@@ -1987,6 +2118,21 @@ ERR PKImageDecode_Copy_WMP(
             Call(pID->WMP.wmiSCP.pWStream->GetPos(pID->WMP.wmiSCP.pWStream, &(pID->WMP.cMarker)));
             FailIf(ICERR_OK != ImageStrDecInit(&pID->WMP.wmiI, &pID->WMP.wmiSCP, &pID->WMP.ctxSC), WMP_errFail);
         }
+
+        // In "Low Memory mode", we don't have full frame buffer. We therefore cannot rotate the image
+        // piecemeal, but we can when asked for all of it at once: every MB row goes to the whole buffer.
+        // We can flip H, V and HV in any case.
+        if (pID->WMP.wmiI.oOrientation >= O_RCW)
+        {
+            FailIf(0 != pRect->X || 0 != pRect->Y ||
+                (I32) pID->WMP.wmiI.cROIHeight != pRect->Width || (I32) pID->WMP.wmiI.cROIWidth != pRect->Height, WMP_errInvalidParameter);
+            // The decoder counts rows before rotation
+            rcRotated = *pRect;
+            rcRotated.Width = pRect->Height;
+            rcRotated.Height = pRect->Width;
+            pRect = &rcRotated;
+        }
+
         // Re-entrant mode incurs 1 MBR delay, so to get 0th MBR, we have to ask for 1st MBR
         cMBRow = ((U32) pID->WMP.cLinesCropped + pRect->Y + pRect->Height +          
             (pRect->Y + pRect->Height >= (I32) pID->WMP.wmiI.cROIHeight ? linesperMBRow - 1 : 0)) / // round up if last MBR
@@ -2004,10 +2150,6 @@ ERR PKImageDecode_Copy_WMP(
             FailIf(ICERR_OK != ImageStrDecInit(&pID->WMP.wmiI, &pID->WMP.wmiSCP, &pID->WMP.ctxSC), WMP_errFail);
         }
 
-        // In "Low Memory mode", we don't have full frame buffer. We therefore cannot rotate the image.
-        // We can flip H, V and HV, but no rotations.
-        FailIf(pID->WMP.wmiI.oOrientation >= O_RCW, WMP_errFail);
-
         // In low-memory mode, the full frame buffer is unavailable. This doesn't seem to
         // matter in O_NONE and O_FLIPH, but for O_FLIPV and O_FLIPVH, outputMBRow tries to write to
         // the bottom of full-frame buffer. Adjust the buffer pointer to compensate.
@@ -2119,6 +2261,7 @@ ERR PKImageDecode_Copy_WMP(
         {
             pID->WMP.DecoderCurrAlphaMBRow = 0;
             FailIf(ICERR_OK != ImageStrDecTerm(pID->WMP.ctxSC_Alpha), WMP_errFail);
+            Call(pWS->SetPos(pWS, pID->WMP.wmiDEMisc.uAlphaOffset));
             FailIf(ICERR_OK != ImageStrDecInit(&pID->WMP.wmiI_Alpha, &pID->WMP.wmiSCP_Alpha, &pID->WMP.ctxSC_Alpha), WMP_errFail);
         }
 
@@ -2145,6 +2288,19 @@ ERR PKImageDecode_Copy_WMP(
 
     pID->idxCurrentLine += pRect->Height;
 
+#ifdef REENTRANT_MODE
+    // Once the last row is out the contexts are gone, so the next call starts over
+    if (cMBRow > 0 && linesperMBRow * (cMBRow - 1) >= (U32) pID->WMP.cLinesCropped + pID->WMP.wmiI.cROIHeight)
+    {
+        pID->WMP.DecoderCurrMBRow = 0;
+        pID->WMP.DecoderCurrAlphaMBRow = 0;
+        pID->WMP.cLinesDecoded = 0;
+        pID->WMP.cLinesCropped = 0;
+        pID->WMP.fFirstNonZeroDecode = FALSE;
+        Call(pWS->SetPos(pWS, pID->WMP.cMarker));
+    }
+#endif // REENTRANT_MODE
+
 Cleanup:
     return err;
 }
@@ -2237,21 +2393,7 @@ ERR PKImageDecode_Release_WMP(PKImageDecode** ppID)
 
     pID = *ppID;
 
-    // Free descriptive metadata
-    FreeDescMetadata(&pID->WMP.sDescMetadata.pvarImageDescription);
-    FreeDescMetadata(&pID->WMP.sDescMetadata.pvarCameraMake);
-    FreeDescMetadata(&pID->WMP.sDescMetadata.pvarCameraModel);
-    FreeDescMetadata(&pID->WMP.sDescMetadata.pvarSoftware);
-    FreeDescMetadata(&pID->WMP.sDescMetadata.pvarDateTime);
-    FreeDescMetadata(&pID->WMP.sDescMetadata.pvarArtist);
-    FreeDescMetadata(&pID->WMP.sDescMetadata.pvarCopyright);
-    FreeDescMetadata(&pID->WMP.sDescMetadata.pvarRatingStars);
-    FreeDescMetadata(&pID->WMP.sDescMetadata.pvarRatingValue);
-    FreeDescMetadata(&pID->WMP.sDescMetadata.pvarCaption);
-    FreeDescMetadata(&pID->WMP.sDescMetadata.pvarDocumentName);
-    FreeDescMetadata(&pID->WMP.sDescMetadata.pvarPageName);
-    FreeDescMetadata(&pID->WMP.sDescMetadata.pvarPageNumber);
-    FreeDescMetadata(&pID->WMP.sDescMetadata.pvarHostComputer);
+    FreeDecoderMetadata(pID);
 
     // Release base class
     Call(PKImageDecode_Release(ppID));
@@ -2276,6 +2418,7 @@ ERR PKImageDecode_Create_WMP(PKImageDecode** ppID)
     pID->Copy = PKImageDecode_Copy_WMP;
     pID->GetColorContext = PKImageDecode_GetColorContext_WMP;
     pID->GetDescriptiveMetadata = PKImageDecode_GetDescriptiveMetadata_WMP;
+    pID->SelectFrame = PKImageDecode_SelectFrame_WMP;
     pID->Release = PKImageDecode_Release_WMP;
 
 Cleanup:
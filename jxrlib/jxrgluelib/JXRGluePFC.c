@@ -469,7 +469,6 @@ ERR RGB96Float_RGB128Fixed(PKFormatConverter* pFC, const PKRect* pRect, U8* pb,
 
     UNREFERENCED_PARAMETER( pFC );
 
-    assert(iWidth > 2); // Otherwise, we corrupt source data in inner loop
     for (y = iHeight - 1; y >= 0; y--)
     {
         I32 x;
@@ -478,9 +477,14 @@ ERR RGB96Float_RGB128Fixed(PKFormatConverter* pFC, const PKRect* pRect, U8* pb,
 
         for (x = iWidth - 1; x >= 0; x--)
         {
-            piDstPixel[4*x] = (I32)(pfltSrcPixel[3*x] * fltCvtFactor + 0.5F);
-            piDstPixel[4*x+1] = (I32)(pfltSrcPixel[3*x+1] * fltCvtFactor + 0.5F);
-            piDstPixel[4*x+2] = (I32)(pfltSrcPixel[3*x+2] * fltCvtFactor + 0.5F);
+            // Read the whole pixel first; the first pixels overlap
+            const float r = pfltSrcPixel[3*x];
+            const float g = pfltSrcPixel[3*x+1];
+            const float b = pfltSrcPixel[3*x+2];
+
+            piDstPixel[4*x] = (I32)(r * fltCvtFactor + 0.5F);
+            piDstPixel[4*x+1] = (I32)(g * fltCvtFactor + 0.5F);
+            piDstPixel[4*x+2] = (I32)(b * fltCvtFactor + 0.5F);
             piDstPixel[4*x+3] = 0; // Zero out the alpha channel
         }
     }
@@ -497,7 +501,6 @@ ERR RGB96Float_RGB128Float(PKFormatConverter* pFC, const PKRect* pRect, U8* pb,
 
     UNREFERENCED_PARAMETER( pFC );
 
-    assert(iWidth > 2); // Otherwise, we corrupt source data in inner loop
     for (y = iHeight - 1; y >= 0; y--)
     {
         I32 x;
@@ -506,9 +509,14 @@ ERR RGB96Float_RGB128Float(PKFormatConverter* pFC, const PKRect* pRect, U8* pb,
 
         for (x = iWidth - 1; x >= 0; x--)
         {
-            pfltDstPixel[4*x] = pfltSrcPixel[3*x];
-            pfltDstPixel[4*x+1] = pfltSrcPixel[3*x+1];
-            pfltDstPixel[4*x+2] = pfltSrcPixel[3*x+2];
+            // Read the whole pixel first; the first pixels overlap
+            const float r = pfltSrcPixel[3*x];
+            const float g = pfltSrcPixel[3*x+1];
+            const float b = pfltSrcPixel[3*x+2];
+
+            pfltDstPixel[4*x] = r;
+            pfltDstPixel[4*x+1] = g;
+            pfltDstPixel[4*x+2] = b;
             pfltDstPixel[4*x+3] = 0.0F; // Zero out the alpha channel
         }
     }
@@ -551,7 +559,6 @@ ERR RGB48Half_RGB64Half(PKFormatConverter* pFC, const PKRect* pRect, U8* pb, U32
 
     UNREFERENCED_PARAMETER( pFC );
 
-    assert(iWidth > 2); // Otherwise, we corrupt source data in inner loop
     for (y = iHeight - 1; y >= 0; y--)
     {
         I32 x;
@@ -560,9 +567,14 @@ ERR RGB48Half_RGB64Half(PKFormatConverter* pFC, const PKRect* pRect, U8* pb, U32
 
         for (x = iWidth - 1; x >= 0; x--)
         {
-            piDstPixel[4*x] = piSrcPixel[3*x];
-            piDstPixel[4*x+1] = piSrcPixel[3*x+1];
-            piDstPixel[4*x+2] = piSrcPixel[3*x+2];
+            // Read the whole pixel first; the first pixels overlap
+            const I16 c0 = piSrcPixel[3*x];
+            const I16 c1 = piSrcPixel[3*x+1];
+            const I16 c2 = piSrcPixel[3*x+2];
+
+            piDstPixel[4*x] = c0;
+            piDstPixel[4*x+1] = c1;
+            piDstPixel[4*x+2] = c2;
             piDstPixel[4*x+3] = 0; // Zero out the alpha channel
         }
     }
@@ -605,7 +617,6 @@ ERR BGR24_BGR32(PKFormatConverter* pFC, const PKRect* pRect, U8* pb, U32 cbStrid
 
     UNREFERENCED_PARAMETER( pFC );
 
-    assert(iWidth > 2); // Otherwise, we corrupt source data in inner loop
     for (y = iHeight - 1; y >= 0; y--)
     {
         I32 x;
@@ -614,9 +625,14 @@ ERR BGR24_BGR32(PKFormatConverter* pFC, const PKRect* pRect, U8* pb, U32 cbStrid
 
         for (x = iWidth - 1; x >= 0; x--)
         {
-            piDstPixel[4*x] = piSrcPixel[3*x];
-            piDstPixel[4*x+1] = piSrcPixel[3*x+1];
-            piDstPixel[4*x+2] = piSrcPixel[3*x+2];
+            // Read the whole pixel first; the first pixels overlap
+            const U8 c0 = piSrcPixel[3*x];
+            const U8 c1 = piSrcPixel[3*x+1];
+            const U8 c2 = piSrcPixel[3*x+2];
+
+            piDstPixel[4*x] = c0;
+            piDstPixel[4*x+1] = c1;
+            piDstPixel[4*x+2] = c2;
             piDstPixel[4*x+3] = 0; // Zero out the alpha channel
         }
     }
@@ -812,9 +828,14 @@ ERR RGB64Fixed_RGB96Float(PKFormatConverter* pFC, const PKRect* pRect, U8* pb, U
 
         for (x = iWidth - 1; x >= 0; x--)
         {
-            pfltDstPixel[3*x] = piSrcPixel[4*x] * fltCvtFactor;
-            pfltDstPixel[3*x+1] = piSrcPixel[4*x+1] * fltCvtFactor;
-            pfltDstPixel[3*x+2] = piSrcPixel[4*x+2] * fltCvtFactor;
+            // Read the whole pixel first; the first pixels overlap
+            const I16 r = piSrcPixel[4*x];
+            const I16 g = piSrcPixel[4*x+1];
+            const I16 b = piSrcPixel[4*x+2];
+
+            pfltDstPixel[3*x] = r * fltCvtFactor;
+            pfltDstPixel[3*x+1] = g * fltCvtFactor;
+            pfltDstPixel[3*x+2] = b * fltCvtFactor;
         }
     }
     
@@ -1073,9 +1094,14 @@ ERR RGB64Half_RGB96Float(PKFormatConverter* pFC, const PKRect* pRect, U8* pb, U3
 
         for (x = iWidth - 1; x >= 0; x--)
         {
-            pfltDstPixel[3*x] = Convert_Half_To_Float(piSrcPixel[4*x]);
-            pfltDstPixel[3*x+1] = Convert_Half_To_Float(piSrcPixel[4*x+1]);
-            pfltDstPixel[3*x+2] = Convert_Half_To_Float(piSrcPixel[4*x+2]);
+            // Read the whole pixel first; the first pixels overlap
+            const I16 r = piSrcPixel[4*x];
+            const I16 g = piSrcPixel[4*x+1];
+            const I16 b = piSrcPixel[4*x+2];
+
+            pfltDstPixel[3*x] = Convert_Half_To_Float(r);
+            pfltDstPixel[3*x+1] = Convert_Half_To_Float(g);
+            pfltDstPixel[3*x+2] = Convert_Half_To_Float(b);
         }
     }
     
@@ -190,6 +190,8 @@ typedef struct tagWmpDEMisc
     U32 uPhotoshopMetadataByteCount;
     U32 uDescMetadataOffset;
     U32 uDescMetadataByteCount;
+
+    U32 uOffNextIFD; // where the next frame's PFD offset goes
 } WmpDEMisc;
 
 
@@ -37,10 +37,12 @@
 
 
 use std::convert::TryFrom;
-use std::io::{self, Read, Seek, SeekFrom};
-use std::ffi::{NulError, c_void};
+use std::fs::File;
+use std::io::{self, Cursor, Read, Write, Seek, SeekFrom};
+use std::ffi::{CStr, CString, NulError, c_void};
 
 // Pull in the C library via bindgen
+#[allow(clippy::upper_case_acronyms, clippy::duplicated_attributes)]
 mod jpegxr_sys;
 use jpegxr_sys::*;
 
@@ -77,6 +79,64 @@ pub enum JXRError {
     UnrecognizedInterpretation,
     #[error("unrecognized bit depth")]
     UnrecognizedBitDepth,
+    #[error("unrecognized bitstream format")]
+    UnrecognizedBitstreamFormat,
+    #[error("unrecognized overlap")]
+    UnrecognizedOverlap,
+    #[error("unrecognized orientation")]
+    UnrecognizedOrientation,
+    #[error("buffer too small")]
+    BufferTooSmall,
+    #[error("quality must be between 0.0 and 1.0")]
+    InvalidQuality,
+    #[error("quality cannot be combined with explicit quantizers")]
+    QualityWithQuantizers,
+    #[error("pixel format has no chroma channels to quantize")]
+    NoChromaChannels,
+    #[error("pixel format has no alpha channel to quantize")]
+    NoAlphaChannel,
+    #[error("interleaved alpha not supported for this pixel format")]
+    InterleavedAlphaNotSupported,
+    #[error("pixel format cannot be stored losslessly")]
+    LosslessNotSupported,
+    #[error("chroma subsampling not supported for this pixel format")]
+    ChromaSubsamplingNotSupported,
+    #[error("float precision not supported for this pixel format")]
+    PrecisionNotSupported,
+    #[error("invalid float precision")]
+    InvalidPrecision,
+    #[error("flexbit trim must be between 0 and 15")]
+    InvalidFlexbitTrim,
+    #[error("polarity only applies to 1-bit pixel formats")]
+    PolarityNotSupported,
+    #[error("lossless encoding requested with lossy options")]
+    LosslessWithLossyOptions,
+    #[error("number of rows written does not match image height")]
+    RowCountMismatch,
+    #[error("invalid rate control target")]
+    InvalidRateTarget,
+    #[error("target size is too small to reach")]
+    TargetSizeUnreachable,
+    #[error("metadata text is not ASCII")]
+    NonAsciiMetadata,
+    #[error("resolution must be positive and finite")]
+    InvalidResolution,
+    #[error("no conversion from {from:?} to {to:?}; possible targets are {possible:?}")]
+    NoConversion {
+        from: PixelFormat,
+        to: PixelFormat,
+        possible: Vec<PixelFormat>,
+    },
+    #[error("invalid tile layout")]
+    InvalidTileLayout,
+    #[error("too many tiles")]
+    TooManyTiles,
+    #[error("scale must be 1, 2, 4, 8 or 16")]
+    InvalidScale,
+    #[error("skipping subbands requires a frequency-ordered file")]
+    SubbandNeedsFrequencyOrder,
+    #[error("deblocking strength must be between 0 and 4")]
+    InvalidDeblocking,
 
     // C-side errors
     #[error("unknown error")]
@@ -148,6 +208,24 @@ fn call(err: ERR) -> Result<()> {
     }
 }
 
+///
+/// Internal helper: check that a buffer holds the given number of
+/// rows, stride bytes apart, each with row_bytes of pixel data.
+///
+fn check_rows(len: usize, stride: usize, rows: usize, row_bytes: usize) -> Result<()> {
+    if rows == 0 {
+        return Ok(());
+    }
+    if stride < row_bytes {
+        return Err(BufferTooSmall);
+    }
+    let needed = stride.checked_mul(rows - 1).and_then(|n| n.checked_add(row_bytes));
+    match needed {
+        Some(needed) if len >= needed => Ok(()),
+        _ => Err(BufferTooSmall)
+    }
+}
+
 ///
 /// Pixel format enum so you don't have to deal with GUIDs yourself.
 /// Naming of these may change before 1.0, be warned.
@@ -302,7 +380,7 @@ static GUID_MAP: &[(&GUID, PixelFormat)] = unsafe {
         (&GUID_PKPixelFormat128bppRGBFixedPoint, PixelFormat128bppRGBFixedPoint),
         (&GUID_PKPixelFormat64bppRGBAHalf, PixelFormat64bppRGBAHalf),
         (&GUID_PKPixelFormat64bppRGBHalf, PixelFormat64bppRGBHalf),
-        (&GUID_PKPixelFormat48bppRGB, PixelFormat48bppRGBHalf),
+        (&GUID_PKPixelFormat48bppRGBHalf, PixelFormat48bppRGBHalf),
         (&GUID_PKPixelFormat32bppRGBE, PixelFormat32bppRGBE),
         (&GUID_PKPixelFormat16bppGrayHalf, PixelFormat16bppGrayHalf),
         (&GUID_PKPixelFormat32bppGrayFixedPoint, PixelFormat32bppGrayFixedPoint),
@@ -386,6 +464,27 @@ impl PixelFormat {
         Err(UnrecognizedPixelFormat)
     }
 
+    ///
+    /// List the formats that pixels in this format can be
+    /// converted to, for ImageEncode::write_pixels_from.
+    ///
+    pub fn conversions(&self) -> Vec<PixelFormat> {
+        let mut formats = Vec::new();
+        let mut index = 0;
+        loop {
+            let mut target: *const GUID = std::ptr::null();
+            let err = unsafe { PKFormatConverter_EnumConversions(self.guid(), index, &mut target) };
+            if err < 0 {
+                break;
+            }
+            if let Ok(format) = unsafe { PixelFormat::from_guid(&*target) } {
+                formats.push(format);
+            }
+            index += 1;
+        }
+        formats
+    }
+
 }
 
 #[derive(Debug, Eq, PartialEq, Clone, Copy)]
@@ -490,6 +589,270 @@ impl BitDepthBits {
     }
 }
 
+///
+/// Meaning of the bits in 1-bit BlackWhite pixel data, which is
+/// packed eight pixels to a byte, leftmost pixel in the high bit.
+/// The bits are stored as they are either way; the polarity is
+/// recorded in the file, as bit depth One or OneAlt, so decoders
+/// know which way round to show them.
+///
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Polarity {
+    /// Set bits are white, as in the bit depth One.
+    WhiteIsOne,
+    /// Set bits are black, as in the bit depth OneAlt and
+    /// most fax and document scans.
+    BlackIsOne,
+}
+
+impl Polarity {
+    fn from_raw(black_white: Bool) -> Polarity {
+        if black_white != 0 {
+            Polarity::BlackIsOne
+        } else {
+            Polarity::WhiteIsOne
+        }
+    }
+
+    fn to_raw(self) -> Bool {
+        (self == Polarity::BlackIsOne) as Bool
+    }
+
+    ///
+    /// Pack a row of 8-bit grayscale pixels into 1-bit pixels,
+    /// where values of 128 and up are white. The packed row must
+    /// hold at least one bit per pixel; unused bits are cleared.
+    ///
+    pub fn pack_row(self, gray: &[u8], packed: &mut [u8]) -> Result<()> {
+        if packed.len() < gray.len().div_ceil(8) {
+            return Err(BufferTooSmall);
+        }
+        let set_if_white = self == Polarity::WhiteIsOne;
+        for (byte, pixels) in packed.iter_mut().zip(gray.chunks(8)) {
+            *byte = pixels.iter().enumerate().fold(0, |byte, (i, &pixel)| {
+                byte | (((pixel >= 128) == set_if_white) as u8) << (7 - i)
+            });
+        }
+        Ok(())
+    }
+
+    ///
+    /// Unpack a row of 1-bit pixels into 8-bit grayscale,
+    /// as 0 for black and 255 for white, filling the gray row.
+    ///
+    pub fn unpack_row(self, packed: &[u8], gray: &mut [u8]) -> Result<()> {
+        if packed.len() < gray.len().div_ceil(8) {
+            return Err(BufferTooSmall);
+        }
+        let set_if_white = self == Polarity::WhiteIsOne;
+        for (i, pixel) in gray.iter_mut().enumerate() {
+            let set = (packed[i / 8] >> (7 - i % 8)) & 1 != 0;
+            *pixel = if set == set_if_white { 255 } else { 0 };
+        }
+        Ok(())
+    }
+}
+
+///
+/// Overlap filtering across macroblock edges. More levels
+/// smooth out blocking artifacts at low quality; none is fastest.
+///
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Overlap {
+    None,
+    One,
+    Two,
+}
+
+impl Overlap {
+    fn from_raw(raw: OVERLAP) -> Result<Overlap> {
+        match raw {
+            OVERLAP_OL_NONE => Ok(Overlap::None),
+            OVERLAP_OL_ONE => Ok(Overlap::One),
+            OVERLAP_OL_TWO => Ok(Overlap::Two),
+            _ => Err(UnrecognizedOverlap)
+        }
+    }
+
+    fn to_raw(self) -> OVERLAP {
+        match self {
+            Overlap::None => OVERLAP_OL_NONE,
+            Overlap::One => OVERLAP_OL_ONE,
+            Overlap::Two => OVERLAP_OL_TWO,
+        }
+    }
+}
+
+///
+/// Frequency bands kept in the codestream. Each transform level
+/// is coded as a band: DC holds one value per macroblock, lowpass
+/// the 4x4 block averages, and highpass the detail within blocks,
+/// with the least significant highpass bits split out as flexbits.
+///
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Subband {
+    /// Keep everything.
+    All,
+    /// Drop the flexbits.
+    NoFlexbits,
+    /// Drop highpass and flexbits, leaving 1/4 resolution detail.
+    NoHighpass,
+    /// Keep only DC, leaving 1/16 resolution detail.
+    DcOnly,
+}
+
+impl Subband {
+    fn to_raw(self) -> SUBBAND {
+        match self {
+            Subband::All => SUBBAND_SB_ALL,
+            Subband::NoFlexbits => SUBBAND_SB_NO_FLEXBITS,
+            Subband::NoHighpass => SUBBAND_SB_NO_HIGHPASS,
+            Subband::DcOnly => SUBBAND_SB_DC_ONLY,
+        }
+    }
+}
+
+///
+/// Resolution of the chroma channels when encoding RGB data,
+/// which is converted to YUV internally. Subsampling can shrink
+/// photos considerably, but is never lossless.
+///
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum ChromaSubsampling {
+    /// Half resolution both ways.
+    YUV420,
+    /// Half resolution horizontally.
+    YUV422,
+    /// Full resolution.
+    YUV444,
+}
+
+///
+/// How 32-bit samples are reduced to the codec's internal range.
+///
+/// Float formats are stored as custom floats with a shorter
+/// mantissa, keeping mantissa_or_shift bits of it (up to 19),
+/// with the exponent offset by exp_bias; larger biases keep
+/// full precision further down towards zero. 32-bit integer
+/// and fixed-point formats are shifted right by 4 to 31 bits,
+/// and exp_bias doesn't apply. Longer mantissas or shorter
+/// shifts overflow the codec on extreme values.
+///
+/// Zero leaves a field at the codec default: 13 mantissa bits
+/// and a bias of 4 for floats, a shift of 10 for the others.
+/// The codec can't store a bias of -128, so that is rejected.
+/// Precision is cut before quantization, so lower quality
+/// settings lose more on top of this.
+///
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Default)]
+pub struct FloatPrecision {
+    pub mantissa_or_shift: u8,
+    pub exp_bias: i8,
+}
+
+impl FloatPrecision {
+    ///
+    /// For scRGB HDR images meant for display, such as screenshots:
+    /// under 0.1% error from 0.001 (0.08 nits) up past 10,000 nits,
+    /// in files smaller than with the default.
+    ///
+    pub const SCRGB_DISPLAY: Self = Self {
+        mantissa_or_shift: 10,
+        exp_bias: 10,
+    };
+
+    ///
+    /// For scRGB HDR masters to be edited further: around 0.001%
+    /// error from 0.0001 up, much finer than half floats.
+    ///
+    pub const SCRGB_MASTER: Self = Self {
+        mantissa_or_shift: 16,
+        exp_bias: 14,
+    };
+}
+
+///
+/// Order of data in the codestream. Spatial order stores each
+/// macroblock whole; frequency order stores the image's lowpass
+/// data first and the high-frequency detail last, so a truncated
+/// file can still be decoded at lower fidelity.
+///
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum BitstreamFormat {
+    Spatial,
+    Frequency,
+}
+
+impl BitstreamFormat {
+    fn from_raw(raw: BITSTREAMFORMAT) -> Result<BitstreamFormat> {
+        match raw {
+            BITSTREAMFORMAT_SPATIAL => Ok(BitstreamFormat::Spatial),
+            BITSTREAMFORMAT_FREQUENCY => Ok(BitstreamFormat::Frequency),
+            _ => Err(UnrecognizedBitstreamFormat)
+        }
+    }
+
+    fn to_raw(self) -> BITSTREAMFORMAT {
+        match self {
+            BitstreamFormat::Spatial => BITSTREAMFORMAT_SPATIAL,
+            BitstreamFormat::Frequency => BITSTREAMFORMAT_FREQUENCY,
+        }
+    }
+}
+
+///
+/// How to turn the stored pixels to display them, as recorded in
+/// the file: a quarter turn clockwise for the Rotate90 variants,
+/// which also swap width and height, then any flips.
+///
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Orientation {
+    None,
+    FlipV,
+    FlipH,
+    FlipVH,
+    Rotate90,
+    Rotate90FlipV,
+    Rotate90FlipH,
+    Rotate90FlipVH,
+}
+
+impl Orientation {
+    fn from_raw(raw: ORIENTATION) -> Result<Orientation> {
+        match raw {
+            ORIENTATION_O_NONE => Ok(Orientation::None),
+            ORIENTATION_O_FLIPV => Ok(Orientation::FlipV),
+            ORIENTATION_O_FLIPH => Ok(Orientation::FlipH),
+            ORIENTATION_O_FLIPVH => Ok(Orientation::FlipVH),
+            ORIENTATION_O_RCW => Ok(Orientation::Rotate90),
+            ORIENTATION_O_RCW_FLIPV => Ok(Orientation::Rotate90FlipV),
+            ORIENTATION_O_RCW_FLIPH => Ok(Orientation::Rotate90FlipH),
+            ORIENTATION_O_RCW_FLIPVH => Ok(Orientation::Rotate90FlipVH),
+            _ => Err(UnrecognizedOrientation)
+        }
+    }
+
+    fn to_raw(self) -> ORIENTATION {
+        match self {
+            Orientation::None => ORIENTATION_O_NONE,
+            Orientation::FlipV => ORIENTATION_O_FLIPV,
+            Orientation::FlipH => ORIENTATION_O_FLIPH,
+            Orientation::FlipVH => ORIENTATION_O_FLIPVH,
+            Orientation::Rotate90 => ORIENTATION_O_RCW,
+            Orientation::Rotate90FlipV => ORIENTATION_O_RCW_FLIPV,
+            Orientation::Rotate90FlipH => ORIENTATION_O_RCW_FLIPH,
+            Orientation::Rotate90FlipVH => ORIENTATION_O_RCW_FLIPVH,
+        }
+    }
+
+    ///
+    /// Whether width and height trade places.
+    ///
+    pub fn is_rotated(self) -> bool {
+        self.to_raw() >= ORIENTATION_O_RCW
+    }
+}
+
 pub struct PixelInfo {
     raw: PKPixelInfo
 }
@@ -542,6 +905,21 @@ impl PixelInfo {
         self.raw.cbitUnit as usize
     }
 
+    ///
+    /// Internal: bytes of pixel data in each row of a buffer for an
+    /// image this wide, and how many rows it has. Subsampled YCC
+    /// formats are stored in units of 2x1 (4:2:2) or 2x2 (4:2:0)
+    /// pixels, so a 4:2:0 row holds two lines of the image.
+    ///
+    fn buffer_rows(&self, width: usize, height: usize) -> (usize, usize) {
+        let unit_bytes = self.bits_per_pixel().div_ceil(8);
+        match self.color_format() {
+            ColorFormat::YUV420 => (width.div_ceil(2) * unit_bytes, height.div_ceil(2)),
+            ColorFormat::YUV422 => (width.div_ceil(2) * unit_bytes, height),
+            _ => ((width * self.bits_per_pixel()).div_ceil(8), height)
+        }
+    }
+
     pub fn has_alpha(&self) -> bool {
         (self.raw.grBit as u32 & PK_pixfmtHasAlpha) != 0
     }
@@ -600,6 +978,10 @@ impl<R> InputStream<R> where R: Read + Seek {
         *reader.unwrap()
     }
 
+    fn reader_mut(&mut self) -> &mut R {
+        self.reader.as_mut().unwrap()
+    }
+
     unsafe fn get_reader(me: *mut WMPStream) -> *mut R {
         std::mem::transmute((*me).state.pvObj)
     }
@@ -613,9 +995,21 @@ impl<R> InputStream<R> where R: Read + Seek {
         let reader = Self::get_reader(me);
         let bytes: *mut u8 = std::mem::transmute(dest);
         let dest_slice = std::slice::from_raw_parts_mut(bytes, cb);
-        match (*reader).read_exact(dest_slice) {
-            Ok(_) => WMP_errSuccess as ERR,
-            Err(_) => WMP_errFileIO as ERR
+        // Like fread(), fill in as much as we can before reporting a short
+        // read; the codec reads whole packets and may run past the end.
+        let mut filled = 0;
+        while filled < cb {
+            match (*reader).read(&mut dest_slice[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => return WMP_errFileIO as ERR
+            }
+        }
+        if filled == cb {
+            WMP_errSuccess as ERR
+        } else {
+            WMP_errFileIO as ERR
         }
     }
 
@@ -648,6 +1042,277 @@ impl<R> InputStream<R> where R: Read + Seek {
     }
 }
 
+///
+/// Internal wrapper around a Write + Seek output file
+/// into a write-only WMPStream the C library can grok.
+///
+struct OutputStream<W: Write + Seek> {
+    raw: Box<WMPStream>,
+    writer: Option<Box<W>>
+}
+
+impl<W> OutputStream<W> where W: Write + Seek {
+    fn new(writer: W) -> Self {
+        let mut boxed_writer = Box::new(writer);
+        let stream = Self {
+            raw: Box::new(WMPStream {
+                state: WMPStream__bindgen_ty_1 {
+                    pvObj: boxed_writer.as_mut() as *mut W as *mut c_void,
+                },
+                fMem: 0,
+                Close: Some(Self::output_stream_close),
+                EOS: None, // Not used in library code base!
+                Read: Some(Self::output_stream_read),
+                Write: Some(Self::output_stream_write),
+                SetPos: Some(Self::output_stream_set_pos),
+                GetPos: Some(Self::output_stream_get_pos)
+            }),
+            writer: Some(boxed_writer)
+        };
+        stream
+    }
+
+    pub fn into_writer(mut self) -> W {
+        let mut writer: Option<Box<W>> = None;
+        std::mem::swap(&mut writer, &mut self.writer);
+        *writer.unwrap()
+    }
+
+    unsafe fn get_writer(me: *mut WMPStream) -> *mut W {
+        std::mem::transmute((*me).state.pvObj)
+    }
+
+    unsafe extern "C" fn output_stream_close(_me: *mut *mut WMPStream) -> ERR {
+        // Do nothing -- we'll free the writer from the Rust side
+        WMP_errSuccess as ERR
+    }
+
+    unsafe extern "C" fn output_stream_read(_me: *mut WMPStream, _dest: *mut c_void, _cb: usize) -> ERR {
+        WMP_errFileIO as ERR
+    }
+
+    unsafe extern "C" fn output_stream_write(me: *mut WMPStream, src: *const c_void, cb: usize) -> ERR {
+        let writer = Self::get_writer(me);
+        let bytes: *const u8 = std::mem::transmute(src);
+        let src_slice = std::slice::from_raw_parts(bytes, cb);
+        match (*writer).write_all(src_slice) {
+            Ok(_) => WMP_errSuccess as ERR,
+            Err(_) => WMP_errFileIO as ERR
+        }
+    }
+
+    unsafe extern "C" fn output_stream_set_pos(me: *mut WMPStream, off_pos: usize) -> ERR {
+        let writer = Self::get_writer(me);
+        match (*writer).seek(SeekFrom::Start(off_pos as u64)) {
+            Ok(_) => WMP_errSuccess as ERR,
+            Err(_) => WMP_errFileIO as ERR
+        }
+    }
+
+    unsafe extern "C" fn output_stream_get_pos(me: *mut WMPStream, off_pos: *mut usize) -> ERR {
+        let writer = Self::get_writer(me);
+        match (*writer).stream_position() {
+            Ok(pos) => {
+                match usize::try_from(pos) {
+                    Ok(out) => {
+                        *off_pos = out;
+                        WMP_errSuccess as ERR
+                    },
+                    Err(_) => WMP_errFileIO as ERR
+                }
+            },
+            Err(_) => WMP_errFileIO as ERR
+        }
+    }
+}
+
+///
+/// Scratch space for the encoder, kept in memory when small
+/// and spilled to an anonymous temporary file when large.
+///
+enum TempBuffer {
+    Memory(Cursor<Vec<u8>>),
+    File(File),
+}
+
+impl TempBuffer {
+    fn with_size_hint(size: usize, memory_limit: usize) -> Result<Self> {
+        if size <= memory_limit {
+            Ok(TempBuffer::Memory(Cursor::new(Vec::with_capacity(size))))
+        } else {
+            Ok(TempBuffer::File(tempfile::tempfile()?))
+        }
+    }
+}
+
+impl Read for TempBuffer {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            TempBuffer::Memory(cursor) => cursor.read(buf),
+            TempBuffer::File(file) => file.read(buf),
+        }
+    }
+}
+
+impl Write for TempBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            TempBuffer::Memory(cursor) => cursor.write(buf),
+            TempBuffer::File(file) => file.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            TempBuffer::Memory(cursor) => cursor.flush(),
+            TempBuffer::File(file) => file.flush(),
+        }
+    }
+}
+
+impl Seek for TempBuffer {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            TempBuffer::Memory(cursor) => cursor.seek(pos),
+            TempBuffer::File(file) => file.seek(pos),
+        }
+    }
+}
+
+///
+/// Internal wrapper around a TempBuffer into a read-write
+/// WMPStream, reusing the input and output stream callbacks.
+///
+struct TempStream {
+    raw: Box<WMPStream>,
+    _buffer: Box<TempBuffer>
+}
+
+impl TempStream {
+    fn new(buffer: TempBuffer) -> Self {
+        let mut boxed_buffer = Box::new(buffer);
+        Self {
+            raw: Box::new(WMPStream {
+                state: WMPStream__bindgen_ty_1 {
+                    pvObj: boxed_buffer.as_mut() as *mut TempBuffer as *mut c_void,
+                },
+                fMem: 0,
+                Close: Some(InputStream::<TempBuffer>::input_stream_close),
+                EOS: None, // Not used in library code base!
+                Read: Some(InputStream::<TempBuffer>::input_stream_read),
+                Write: Some(OutputStream::<TempBuffer>::output_stream_write),
+                SetPos: Some(InputStream::<TempBuffer>::input_stream_set_pos),
+                GetPos: Some(InputStream::<TempBuffer>::input_stream_get_pos)
+            }),
+            _buffer: boxed_buffer
+        }
+    }
+}
+
+///
+/// Seekable buffer in front of a write-only output such as a pipe,
+/// socket or HTTP response body, for use with ImageEncode. Data is
+/// kept in memory up to a limit, then spilled to an anonymous
+/// temporary file; call finish() once encoding is done to send it
+/// all on to the output.
+///
+pub struct SpooledWriter<W: Write> {
+    spool: tempfile::SpooledTempFile,
+    writer: W
+}
+
+impl<W> SpooledWriter<W> where W: Write {
+    ///
+    /// Wrap the given output, keeping up to DEFAULT_MEMORY_LIMIT
+    /// bytes in memory.
+    ///
+    pub fn new(writer: W) -> Self {
+        Self::with_memory_limit(writer, DEFAULT_MEMORY_LIMIT)
+    }
+
+    ///
+    /// Wrap the given output, keeping up to the given number
+    /// of bytes in memory.
+    ///
+    pub fn with_memory_limit(writer: W, bytes: usize) -> Self {
+        Self {
+            spool: tempfile::spooled_tempfile(bytes),
+            writer
+        }
+    }
+
+    ///
+    /// Copy the buffered data to the output, and return it.
+    ///
+    pub fn finish(mut self) -> Result<W> {
+        self.spool.seek(SeekFrom::Start(0))?;
+        io::copy(&mut self.spool, &mut self.writer)?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+impl<W> Write for SpooledWriter<W> where W: Write {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.spool.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.spool.flush()
+    }
+}
+
+impl<W> Seek for SpooledWriter<W> where W: Write {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.spool.seek(pos)
+    }
+}
+
+///
+/// Internal stand-in for an image decoder, serving pixels from
+/// memory to the format converter. The generic decoder methods
+/// read the format and size from the fields; Copy is ours.
+///
+#[repr(C)]
+struct PixelSource<'a> {
+    raw: PKImageDecode,
+    pixels: &'a [u8],
+    stride: usize,
+    row_bytes: usize,
+}
+
+impl<'a> PixelSource<'a> {
+    fn new(format: PixelFormat, width: u32, height: u32, pixels: &'a [u8], stride: usize) -> Result<Box<Self>> {
+        let info = PixelInfo::from_guid(format.guid())?;
+        let row_bytes = (width as usize * info.bits_per_pixel()).div_ceil(8);
+        check_rows(pixels.len(), stride, height as usize, row_bytes)?;
+        let mut raw: PKImageDecode = unsafe { std::mem::zeroed() };
+        raw.GetPixelFormat = Some(PKImageDecode_GetPixelFormat);
+        raw.GetSize = Some(PKImageDecode_GetSize);
+        raw.Copy = Some(Self::pixel_source_copy);
+        raw.guidPixFormat = *format.guid();
+        raw.uWidth = width;
+        raw.uHeight = height;
+        Ok(Box::new(Self {
+            raw,
+            pixels,
+            stride,
+            row_bytes,
+        }))
+    }
+
+    unsafe extern "C" fn pixel_source_copy(me: *mut PKImageDecode, rect: *const PKRect, dest: *mut U8, stride: U32) -> ERR {
+        // Always called with the whole image.
+        let source = &*(me as *const Self);
+        let (y, height) = ((*rect).Y as usize, (*rect).Height as usize);
+        for row in 0..height {
+            let src = &source.pixels[(y + row) * source.stride..][..source.row_bytes];
+            std::ptr::copy_nonoverlapping(src.as_ptr(), dest.add(row * stride as usize), source.row_bytes);
+        }
+        WMP_errSuccess as ERR
+    }
+}
+
 ///
 /// Coordinate struct for reading a subset of an image.
 /// Pixels are i32.
@@ -701,14 +1366,215 @@ impl Rect {
 }
 
 ///
-/// High-level JPEG XR image decoder struct.
-/// Requires a seekable data source, such as a File.
-/// You can decode multiple subsets of the image,
-/// though this is not yet well-tested.
+/// Descriptive metadata stored in the container, as in TIFF.
 ///
-pub struct ImageDecode<R: Read + Seek> {
+/// Text fields are ASCII, except the caption which takes any
+/// Unicode text; none may contain NUL characters. The date and
+/// time are formatted "YYYY:MM:DD HH:MM:SS". Page numbers are
+/// the page and the total page count, with 0 if unknown.
+///
+#[derive(Debug, Default, Eq, PartialEq, Clone)]
+pub struct DescriptiveMetadata {
+    pub image_description: Option<String>,
+    pub camera_make: Option<String>,
+    pub camera_model: Option<String>,
+    pub software: Option<String>,
+    pub date_time: Option<String>,
+    pub artist: Option<String>,
+    pub copyright: Option<String>,
+    pub rating_stars: Option<u16>,
+    pub rating_value: Option<u16>,
+    pub caption: Option<String>,
+    pub document_name: Option<String>,
+    pub page_name: Option<String>,
+    pub page_number: Option<(u16, u16)>,
+    pub host_computer: Option<String>,
+}
+
+impl DescriptiveMetadata {
+    unsafe fn from_raw(raw: &DESCRIPTIVEMETADATA) -> Self {
+        unsafe fn ascii(var: &DPKPROPVARIANT) -> Option<String> {
+            if var.vt != DPKVARTYPE_DPKVT_LPSTR {
+                return None;
+            }
+            Some(CStr::from_ptr(var.VT.pszVal).to_string_lossy().into_owned())
+        }
+        unsafe fn short(var: &DPKPROPVARIANT) -> Option<u16> {
+            if var.vt != DPKVARTYPE_DPKVT_UI2 {
+                return None;
+            }
+            Some(var.VT.uiVal)
+        }
+        unsafe fn wide(var: &DPKPROPVARIANT) -> Option<String> {
+            if var.vt != DPKVARTYPE_DPKVT_LPWSTR {
+                return None;
+            }
+            let mut len = 0;
+            while *var.VT.pwszVal.add(len) != 0 {
+                len += 1;
+            }
+            Some(String::from_utf16_lossy(std::slice::from_raw_parts(var.VT.pwszVal, len)))
+        }
+        let page_number = match raw.pvarPageNumber.vt {
+            DPKVARTYPE_DPKVT_UI4 => {
+                let value = raw.pvarPageNumber.VT.ulVal;
+                Some((value as u16, (value >> 16) as u16))
+            },
+            _ => None
+        };
+        Self {
+            image_description: ascii(&raw.pvarImageDescription),
+            camera_make: ascii(&raw.pvarCameraMake),
+            camera_model: ascii(&raw.pvarCameraModel),
+            software: ascii(&raw.pvarSoftware),
+            date_time: ascii(&raw.pvarDateTime),
+            artist: ascii(&raw.pvarArtist),
+            copyright: ascii(&raw.pvarCopyright),
+            rating_stars: short(&raw.pvarRatingStars),
+            rating_value: short(&raw.pvarRatingValue),
+            caption: wide(&raw.pvarCaption),
+            document_name: ascii(&raw.pvarDocumentName),
+            page_name: ascii(&raw.pvarPageName),
+            page_number,
+            host_computer: ascii(&raw.pvarHostComputer),
+        }
+    }
+
+    ///
+    /// Internal: build the C struct, with the text it points
+    /// to kept in the given buffers, which must outlive it.
+    ///
+    fn to_raw(&self, strings: &mut Vec<CString>, caption: &mut Vec<u16>) -> Result<DESCRIPTIVEMETADATA> {
+        let mut ascii = |text: &Option<String>| -> Result<DPKPROPVARIANT> {
+            let mut var: DPKPROPVARIANT = unsafe { std::mem::zeroed() };
+            if let Some(text) = text {
+                if !text.is_ascii() {
+                    return Err(NonAsciiMetadata);
+                }
+                let string = CString::new(text.as_str())?;
+                var.vt = DPKVARTYPE_DPKVT_LPSTR;
+                var.VT.pszVal = string.as_ptr() as *mut _;
+                strings.push(string);
+            }
+            Ok(var)
+        };
+        let mut raw = DESCRIPTIVEMETADATA {
+            pvarImageDescription: ascii(&self.image_description)?,
+            pvarCameraMake: ascii(&self.camera_make)?,
+            pvarCameraModel: ascii(&self.camera_model)?,
+            pvarSoftware: ascii(&self.software)?,
+            pvarDateTime: ascii(&self.date_time)?,
+            pvarArtist: ascii(&self.artist)?,
+            pvarCopyright: ascii(&self.copyright)?,
+            pvarRatingStars: unsafe { std::mem::zeroed() },
+            pvarRatingValue: unsafe { std::mem::zeroed() },
+            pvarCaption: unsafe { std::mem::zeroed() },
+            pvarDocumentName: ascii(&self.document_name)?,
+            pvarPageName: ascii(&self.page_name)?,
+            pvarPageNumber: unsafe { std::mem::zeroed() },
+            pvarHostComputer: ascii(&self.host_computer)?,
+        };
+        if let Some(stars) = self.rating_stars {
+            raw.pvarRatingStars.vt = DPKVARTYPE_DPKVT_UI2;
+            raw.pvarRatingStars.VT.uiVal = stars;
+        }
+        if let Some(value) = self.rating_value {
+            raw.pvarRatingValue.vt = DPKVARTYPE_DPKVT_UI2;
+            raw.pvarRatingValue.VT.uiVal = value;
+        }
+        if let Some((page, count)) = self.page_number {
+            raw.pvarPageNumber.vt = DPKVARTYPE_DPKVT_UI4;
+            raw.pvarPageNumber.VT.ulVal = page as u32 | (count as u32) << 16;
+        }
+        if let Some(text) = &self.caption {
+            // Same NUL check as the ASCII fields.
+            CString::new(text.as_str())?;
+            caption.clear();
+            caption.extend(text.encode_utf16());
+            caption.push(0);
+            raw.pvarCaption.vt = DPKVARTYPE_DPKVT_LPWSTR;
+            raw.pvarCaption.VT.pwszVal = caption.as_mut_ptr();
+        }
+        Ok(raw)
+    }
+}
+
+///
+/// Settings for the decoder.
+///
+/// Subband drops detail while decoding, for a cheap blurry preview
+/// ahead of the full decode: without highpass only the 4x4 block
+/// averages are left, and DC only leaves one value per macroblock.
+/// The bands are only stored apart in frequency-ordered files; in
+/// spatial files they're interleaved and have to be read anyway,
+/// so asking for fewer bands than a spatial file holds is an error.
+///
+/// Deblocking smooths the edges between blocks that heavy
+/// quantization leaves behind, from 0 (off) through 1 (light),
+/// 2 (medium) and 3 (strong) to 4 (very strong). The filter
+/// follows each block's quantizer, so it leaves finely quantized
+/// images mostly alone.
+///
+/// With apply_orientation, pixels come out turned the way the
+/// file's orientation says, and sizes are reported that way too.
+/// Flipped images decode as usual, but a rotated one can only be
+/// decoded whole, as each macroblock row of the file lands in a
+/// column of the output. Turn it off to get the stored pixels.
+///
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct DecodeOptions {
+    pub subband: Subband,
+    pub deblocking: u8,
+    pub apply_orientation: bool,
+}
+
+impl Default for DecodeOptions {
+    fn default() -> Self {
+        Self {
+            subband: Subband::All,
+            deblocking: 0,
+            apply_orientation: true,
+        }
+    }
+}
+
+impl DecodeOptions {
+    ///
+    /// Check the options against the current frame and fill out
+    /// the decoder parameters, which take effect when decoding starts.
+    ///
+    unsafe fn apply(&self, codec: *mut PKImageDecode) -> Result<()> {
+        let wmp = &mut (*codec).WMP;
+        // Subbands run from all to DC only, as do their raw values.
+        let fewer = self.subband.to_raw() > wmp.wmiSCP.sbSubband;
+        if fewer && wmp.wmiSCP.bfBitstreamFormat != BITSTREAMFORMAT_FREQUENCY {
+            return Err(SubbandNeedsFrequencyOrder);
+        }
+        if self.deblocking > 4 {
+            return Err(InvalidDeblocking);
+        }
+        wmp.wmiI.sbSubband = self.subband.to_raw();
+        wmp.wmiI.cPostProcStrength = self.deblocking;
+        wmp.wmiI.oOrientation = if self.apply_orientation && wmp.fOrientationFromContainer != 0 {
+            wmp.oOrientationFromContainer
+        } else {
+            ORIENTATION_O_NONE
+        };
+        Ok(())
+    }
+}
+
+///
+/// High-level JPEG XR image decoder struct.
+/// Requires a seekable data source, such as a File.
+/// You can decode multiple subsets of the image,
+/// though this is not yet well-tested.
+///
+pub struct ImageDecode<R: Read + Seek> {
     raw: *mut PKImageDecode,
     stream: Option<InputStream<R>>,
+    frame: u32,
+    options: DecodeOptions,
 }
 
 impl<R> ImageDecode<R> where R: Read + Seek {
@@ -716,19 +1582,117 @@ impl<R> ImageDecode<R> where R: Read + Seek {
     ///
     /// Create a new JPEG XR image decoder for the given input.
     /// This will consume the reader, and free it when done.
+    /// Pixel formats with alpha get their alpha channel decoded
+    /// along with the color.
     ///
     pub fn with_reader(reader: R) -> Result<Self> {
+        let mut stream = InputStream::new(reader);
+        let codec = unsafe { Self::open(&mut stream, 0)? };
+        Ok(Self {
+            raw: codec,
+            stream: Some(stream),
+            frame: 0,
+            options: DecodeOptions::default()
+        })
+    }
+
+    ///
+    /// Internal: create a C decoder for one frame of the input,
+    /// which must be at the start. jxrlib leaves the alpha channel
+    /// out unless told otherwise, so we ask for it here.
+    ///
+    unsafe fn open(stream: &mut InputStream<R>, frame: u32) -> Result<*mut PKImageDecode> {
+        let mut codec: *mut PKImageDecode = std::ptr::null_mut();
+        call(PKImageDecode_Create_WMP(&mut codec as *mut *mut PKImageDecode))?;
+        let result = call((*codec).Initialize.unwrap()(codec, stream.raw.as_mut())).and_then(|_| {
+            if frame > 0 {
+                call((*codec).SelectFrame.unwrap()(codec, frame))
+            } else {
+                Ok(())
+            }
+        });
+        if let Err(e) = result {
+            (*codec).Release.unwrap()(&mut codec);
+            return Err(e);
+        }
+
+        if let Ok(info) = PixelInfo::from_guid(&(*codec).guidPixFormat) {
+            if info.has_alpha() {
+                (*codec).WMP.wmiSCP.uAlphaMode = 2;
+            }
+        }
+        Ok(codec)
+    }
+
+    ///
+    /// Get the number of frames in the file, such as the pages
+    /// of a scanned document. Most files have just one.
+    ///
+    pub fn frame_count(&self) -> Result<u32> {
         unsafe {
-            let mut stream = InputStream::new(reader);
+            let mut count: u32 = 0;
+            call((*self.raw).GetFrameCount.unwrap()(self.raw, &mut count))?;
+            Ok(count)
+        }
+    }
 
-            let mut codec: *mut PKImageDecode = std::ptr::null_mut();
-            call(PKImageDecode_Create_WMP(&mut codec as *mut *mut PKImageDecode))?;
-            call((*codec).Initialize.unwrap()(codec, stream.raw.as_mut()))?;
+    ///
+    /// Switch to another frame, counting from 0. Everything else,
+    /// from the size and pixel format to the metadata, then
+    /// describes that frame.
+    ///
+    pub fn select_frame(&mut self, frame: u32) -> Result<()> {
+        if frame >= self.frame_count()? {
+            return Err(IndexNotFound);
+        }
+        self.reopen(frame)
+    }
 
-            Ok(Self {
-                raw: codec,
-                stream: Some(stream)
+    ///
+    /// Internal: start over with a new decoder on the given frame,
+    /// putting the input back for the old one if that fails.
+    ///
+    fn reopen(&mut self, frame: u32) -> Result<()> {
+        let options = self.options;
+        let stream = self.stream.as_mut().unwrap();
+        let pos = stream.reader_mut().stream_position()?;
+        stream.reader_mut().rewind()?;
+        let result = unsafe {
+            Self::open(stream, frame).and_then(|mut codec| {
+                match options.apply(codec) {
+                    Ok(()) => Ok(codec),
+                    Err(e) => {
+                        (*codec).Release.unwrap()(&mut codec);
+                        Err(e)
+                    }
+                }
             })
+        };
+        match result {
+            Ok(codec) => {
+                unsafe {
+                    (*self.raw).Release.unwrap()(&mut self.raw);
+                }
+                self.raw = codec;
+                self.frame = frame;
+                Ok(())
+            },
+            Err(e) => {
+                stream.reader_mut().seek(SeekFrom::Start(pos))?;
+                Err(e)
+            }
+        }
+    }
+
+    ///
+    /// Internal: throw away a partly finished decode, so the next
+    /// one can start with different settings.
+    ///
+    fn restart(&mut self) -> Result<()> {
+        if unsafe { (*self.raw).WMP.DecoderCurrMBRow } != 0 {
+            self.reopen(self.frame)
+        } else {
+            Ok(())
         }
     }
 
@@ -747,7 +1711,23 @@ impl<R> ImageDecode<R> where R: Read + Seek {
     }
 
     ///
-    /// Get width and height in pixels.
+    /// Get the orientation recorded in the file, which says how
+    /// to turn the stored pixels for display.
+    ///
+    pub fn orientation(&self) -> Result<Orientation> {
+        unsafe {
+            let wmp = &(*self.raw).WMP;
+            if wmp.fOrientationFromContainer != 0 {
+                Orientation::from_raw(wmp.oOrientationFromContainer)
+            } else {
+                Ok(Orientation::None)
+            }
+        }
+    }
+
+    ///
+    /// Get width and height in pixels, swapped if the image
+    /// is decoded rotated.
     ///
     pub fn get_size(&self) -> Result<(i32, i32)> {
         unsafe {
@@ -758,6 +1738,33 @@ impl<R> ImageDecode<R> where R: Read + Seek {
         }
     }
 
+    ///
+    /// Get width and height in pixels of the image decoded at
+    /// 1/scale size, as with copy_scaled. Partial pixels round up.
+    ///
+    pub fn get_scaled_size(&self, scale: u32) -> Result<(i32, i32)> {
+        if !matches!(scale, 1 | 2 | 4 | 8 | 16) {
+            return Err(InvalidScale);
+        }
+        let (width, height) = unsafe { ((*self.raw).uWidth, (*self.raw).uHeight) };
+        let width = i32::try_from(width.div_ceil(scale))?;
+        let height = i32::try_from(height.div_ceil(scale))?;
+        if self.decode_orientation().is_rotated() {
+            Ok((height, width))
+        } else {
+            Ok((width, height))
+        }
+    }
+
+    ///
+    /// Internal: the orientation pixels are decoded with.
+    ///
+    fn decode_orientation(&self) -> Orientation {
+        unsafe {
+            Orientation::from_raw((*self.raw).WMP.wmiI.oOrientation).unwrap_or(Orientation::None)
+        }
+    }
+
     ///
     /// Get horizontal and vertical DPI.
     ///
@@ -770,19 +1777,152 @@ impl<R> ImageDecode<R> where R: Read + Seek {
         }
     }
 
+    ///
+    /// Get the order of data in the codestream.
+    ///
+    pub fn get_bitstream_format(&self) -> Result<BitstreamFormat> {
+        unsafe {
+            BitstreamFormat::from_raw((*self.raw).WMP.wmiSCP.bfBitstreamFormat)
+        }
+    }
+
+    ///
+    /// Get the level of overlap filtering the image was encoded with.
+    ///
+    pub fn get_overlap(&self) -> Result<Overlap> {
+        unsafe {
+            Overlap::from_raw((*self.raw).WMP.wmiSCP.olOverlap)
+        }
+    }
+
+    ///
+    /// Get the polarity of 1-bit BlackWhite pixels. Other formats
+    /// always report WhiteIsOne.
+    ///
+    pub fn get_polarity(&self) -> Result<Polarity> {
+        unsafe {
+            Ok(Polarity::from_raw((*self.raw).WMP.wmiSCP.bBlackWhite))
+        }
+    }
+
+    ///
+    /// Get the descriptive metadata stored in the container.
+    ///
+    pub fn get_descriptive_metadata(&self) -> Result<DescriptiveMetadata> {
+        unsafe {
+            let mut raw: DESCRIPTIVEMETADATA = std::mem::zeroed();
+            call((*self.raw).GetDescriptiveMetadata.unwrap()(self.raw, &mut raw))?;
+            Ok(DescriptiveMetadata::from_raw(&raw))
+        }
+    }
+
+    ///
+    /// Set the decoder options, which apply to every frame.
+    /// A decode already under way starts over.
+    ///
+    pub fn set_options(&mut self, options: &DecodeOptions) -> Result<()> {
+        self.restart()?;
+        unsafe {
+            options.apply(self.raw)?;
+        }
+        self.options = *options;
+        Ok(())
+    }
+
     ///
     /// Decode pixel data and copy it into a provided output buffer.
     /// You can ask for just part of the image to decode fewer macroblocks.
-    /// However this mode is not well tested.
+    /// However this mode is not well tested. Rotated images must be
+    /// decoded whole.
+    ///
+    /// Once a call has copied the bottom row of the image, the
+    /// decoder goes back to the start of the image data, so the
+    /// next call decodes from the top again.
     ///
     pub fn copy(&mut self, rect: &Rect, dest: &mut [u8], stride: usize) -> Result<()> {
         let stride_u32 = u32::try_from(stride)?;
+        if self.decode_orientation().is_rotated() {
+            let (width, height) = self.get_size()?;
+            if (rect.get_x(), rect.get_y(), rect.get_width(), rect.get_height()) != (0, 0, width, height) {
+                return Err(InvalidArgument);
+            }
+        }
         unsafe {
+            self.options.apply(self.raw)?;
             call((*self.raw).Copy.unwrap()(self.raw, &rect.raw, dest.as_mut_ptr(), stride_u32))?;
             Ok(())
         }
     }
 
+    ///
+    /// Decode at 1/2, 1/4, 1/8 or 1/16 size into a provided output
+    /// buffer. Only the transform levels needed for that size are
+    /// run, so this is much cheaper than a full decode. The rect is
+    /// in scaled pixels; see get_scaled_size for the bounds.
+    /// Subsampled YCC output formats can't be scaled. As with copy,
+    /// rotated images must be decoded whole.
+    ///
+    pub fn copy_scaled(&mut self, rect: &Rect, scale: u32, dest: &mut [u8], stride: usize) -> Result<()> {
+        let (width, height) = self.get_scaled_size(scale)?;
+        let (x, y, w, h) = (rect.get_x(), rect.get_y(), rect.get_width(), rect.get_height());
+        if x < 0 || y < 0 || w <= 0 || h <= 0 ||
+            !matches!(x.checked_add(w), Some(right) if right <= width) ||
+            !matches!(y.checked_add(h), Some(bottom) if bottom <= height) {
+            return Err(InvalidArgument);
+        }
+        let info = PixelInfo::from_format(self.get_pixel_format()?);
+        if scale > 1 && matches!(info.color_format(), ColorFormat::YUV420 | ColorFormat::YUV422) {
+            return Err(UnsupportedFormat);
+        }
+        let row_bytes = (w as usize * info.bits_per_pixel()).div_ceil(8);
+        check_rows(dest.len(), stride, h as usize, row_bytes)?;
+        let stride_u32 = u32::try_from(stride)?;
+
+        // The region is given to the decoder in stored pixels,
+        // before any flip or rotation.
+        let orientation = self.decode_orientation();
+        let (x, y, w, h, width, height) = if orientation.is_rotated() {
+            if (x, y, w, h) != (0, 0, width, height) {
+                return Err(InvalidArgument);
+            }
+            (0, 0, h, w, height, width)
+        } else {
+            let x = if matches!(orientation, Orientation::FlipH | Orientation::FlipVH) { width - x - w } else { x };
+            let y = if matches!(orientation, Orientation::FlipV | Orientation::FlipVH) { height - y - h } else { y };
+            (x, y, w, h, width, height)
+        };
+
+        // The scale and region are fixed when decoding starts, and
+        // the region's rows are then counted from 0.
+        self.restart()?;
+        unsafe {
+            self.options.apply(self.raw)?;
+        }
+        let result = unsafe {
+            let wmi = &mut (*self.raw).WMP.wmiI;
+            wmi.cThumbnailWidth = width as usize;
+            wmi.cThumbnailHeight = height as usize;
+            wmi.cROILeftX = x as usize;
+            wmi.cROITopY = y as usize;
+            wmi.cROIWidth = w as usize;
+            wmi.cROIHeight = h as usize;
+            let region = if orientation.is_rotated() { Rect::new(0, 0, h, w) } else { Rect::new(0, 0, w, h) };
+            let result = call((*self.raw).Copy.unwrap()(self.raw, &region.raw, dest.as_mut_ptr(), stride_u32));
+
+            // Back to full size for copy; a failure may leave
+            // decoding under way.
+            let wmi = &mut (*self.raw).WMP.wmiI;
+            wmi.cThumbnailWidth = 0;
+            wmi.cThumbnailHeight = 0;
+            wmi.cROILeftX = 0;
+            wmi.cROITopY = 0;
+            wmi.cROIWidth = 0;
+            wmi.cROIHeight = 0;
+            result
+        };
+        result.and(self.restart())
+    }
+
     ///
     /// Decode the entire image in one go, for convenience.
     ///
@@ -792,6 +1932,45 @@ impl<R> ImageDecode<R> where R: Read + Seek {
         self.copy(&rect, dest, stride)
     }
 
+    ///
+    /// Decode the image in bands of rows, top to bottom, each in
+    /// its own buffer. Only a macroblock strip of 16 rows is held
+    /// besides the band, so huge images can be decoded in little
+    /// memory. The last band may be shorter.
+    ///
+    /// With a vertical flip the bottom band comes first, as the
+    /// bands follow the stored rows. Rotated images can't be decoded
+    /// in bands; turn off DecodeOptions::apply_orientation for the
+    /// stored rows. Subsampled YCC output formats aren't supported.
+    ///
+    pub fn rows(&mut self, band_height: u32) -> Result<Rows<'_, R>> {
+        let orientation = self.decode_orientation();
+        if band_height == 0 || orientation.is_rotated() {
+            return Err(InvalidArgument);
+        }
+        let info = PixelInfo::from_format(self.get_pixel_format()?);
+        if matches!(info.color_format(), ColorFormat::YUV420 | ColorFormat::YUV422) {
+            return Err(UnsupportedFormat);
+        }
+        let (width, height) = self.get_size()?;
+        let stride = (width as usize * info.bits_per_pixel()).div_ceil(8);
+
+        // Rows are counted from the top of the stored image.
+        self.restart()?;
+        Ok(Rows {
+            decoder: self,
+            width,
+            height,
+            band_height: i32::try_from(band_height)?,
+            stride,
+            flip_v: matches!(orientation, Orientation::FlipV | Orientation::FlipVH),
+            strip: vec![0u8; stride * 16],
+            strip_y: 0,
+            strip_height: 0,
+            next_y: 0,
+        })
+    }
+
     ///
     /// Free the image decoder and return the input reader.
     /// Only needed if you want to reuse the same reader struct
@@ -813,45 +1992,2394 @@ impl<R> Drop for ImageDecode<R> where R: Read + Seek {
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use std::fs::{File};
-    use crate::ImageDecode;
-    use crate::PixelFormat::*;
-    use crate::PixelInfo;
-    use crate::ColorFormat;
-    use crate::BitDepthBits;
-    use crate::PhotometricInterpretation;
+///
+/// A band of decoded rows from ImageDecode::rows(), starting
+/// at row y of the image.
+///
+#[derive(Debug, Clone)]
+pub struct Band {
+    pub y: i32,
+    pub height: i32,
+    pub stride: usize,
+    pub pixels: Vec<u8>,
+}
 
-    #[test]
-    fn it_works() {
-        let input_result = File::open("samples/panel-hdr.jxr");
-        assert!(input_result.is_ok());
-        let input = input_result.unwrap();
+///
+/// Iterator over the bands of an image being decoded.
+/// Get one with ImageDecode::rows().
+///
+pub struct Rows<'a, R: Read + Seek> {
+    decoder: &'a mut ImageDecode<R>,
+    width: i32,
+    height: i32,
+    band_height: i32,
+    stride: usize,
+    flip_v: bool,
+    strip: Vec<u8>,
+    strip_y: i32,
+    strip_height: i32,
+    next_y: i32,
+}
 
-        let decoder_result = ImageDecode::with_reader(input);
-        assert!(decoder_result.is_ok());
-        let decoder = decoder_result.unwrap();
+impl<'a, R> Rows<'a, R> where R: Read + Seek {
+    ///
+    /// Internal: decode the next macroblock strip. The decoder
+    /// only writes whole strips, and carries on from the last.
+    ///
+    fn decode_strip(&mut self) -> Result<()> {
+        let y = self.strip_y + self.strip_height;
+        let height = (self.height - y).min(16);
+        let rect = Rect::new(0, y, self.width, height);
+        self.decoder.copy(&rect, &mut self.strip, self.stride)?;
+        self.strip_y = y;
+        self.strip_height = height;
+        Ok(())
+    }
 
-        let size_result = decoder.get_size();
-        assert!(size_result.is_ok());
-        let (width, height) = size_result.unwrap();
-        assert_eq!(width, 3440);
-        assert_eq!(height, 1440);
+    fn next_band(&mut self) -> Result<Band> {
+        let start = self.next_y;
+        let height = (self.height - start).min(self.band_height);
+        let mut pixels = vec![0u8; self.stride * height as usize];
 
-        let pixfmt_result = decoder.get_pixel_format();
-        assert!(pixfmt_result.is_ok());
-        let pixfmt = pixfmt_result.unwrap();
-        assert_eq!(pixfmt, PixelFormat128bppRGBAFloat);
+        // Rows are counted in stored order; with a vertical flip
+        // both the strip and band are filled from the bottom.
+        for row in start..start + height {
+            if row >= self.strip_y + self.strip_height {
+                self.decode_strip()?;
+            }
+            let from = if self.flip_v { self.strip_y + self.strip_height - 1 - row } else { row - self.strip_y };
+            let to = if self.flip_v { start + height - 1 - row } else { row - start };
+            pixels[to as usize * self.stride..][..self.stride]
+                .copy_from_slice(&self.strip[from as usize * self.stride..][..self.stride]);
+        }
+        self.next_y += height;
+        Ok(Band {
+            y: if self.flip_v { self.height - start - height } else { start },
+            height,
+            stride: self.stride,
+            pixels,
+        })
+    }
+}
 
-        let info = PixelInfo::from_format(pixfmt);
-        assert_eq!(info.channels(), 4);
-        assert_eq!(info.color_format(), ColorFormat::RGB);
-        assert_eq!(info.bit_depth(), BitDepthBits::ThirtyTwoF);
-        assert!(info.has_alpha());
-        assert!(!info.premultiplied_alpha());
-        assert!(!info.bgr());
-        assert_eq!(info.photometric_interpretation(), PhotometricInterpretation::RGB);
-        assert_eq!(info.samples_per_pixel(), 4);
+impl<'a, R> Iterator for Rows<'a, R> where R: Read + Seek {
+    type Item = Result<Band>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next_y >= self.height {
+            return None;
+        }
+        let result = self.next_band();
+        if result.is_err() {
+            // Stop after an error.
+            self.next_y = self.height;
+        }
+        Some(result)
+    }
+}
+
+///
+/// Quantizer tables from the JxrEncApp sample tool, used to map
+/// a 0.0-1.0 quality onto the per-band quantizers. Each row is
+/// Y, U, V, Y highpass, U highpass, V highpass, in steps of 0.1.
+///
+const DPK_QPS_420: [[u8; 6]; 11] = [ // for 8 bit only
+    [66, 65, 70, 72, 72, 77],
+    [59, 58, 63, 64, 63, 68],
+    [52, 51, 57, 56, 56, 61],
+    [48, 48, 54, 51, 50, 55],
+    [43, 44, 48, 46, 46, 49],
+    [37, 37, 42, 38, 38, 43],
+    [26, 28, 31, 27, 28, 31],
+    [16, 17, 22, 16, 17, 21],
+    [10, 11, 13, 10, 10, 13],
+    [5, 5, 6, 5, 5, 6],
+    [2, 2, 3, 2, 2, 2],
+];
+
+const DPK_QPS_8: [[u8; 6]; 12] = [
+    [67, 79, 86, 72, 90, 98],
+    [59, 74, 80, 64, 83, 89],
+    [53, 68, 75, 57, 76, 83],
+    [49, 64, 71, 53, 70, 77],
+    [45, 60, 67, 48, 67, 74],
+    [40, 56, 62, 42, 59, 66],
+    [33, 49, 55, 35, 51, 58],
+    [27, 44, 49, 28, 45, 50],
+    [20, 36, 42, 20, 38, 44],
+    [13, 27, 34, 13, 28, 34],
+    [7, 17, 21, 8, 17, 21], // Photoshop 100%
+    [2, 5, 6, 2, 5, 6],
+];
+
+const DPK_QPS_16: [[u8; 6]; 11] = [
+    [197, 203, 210, 202, 207, 213],
+    [174, 188, 193, 180, 189, 196],
+    [152, 167, 173, 156, 169, 174],
+    [135, 152, 157, 137, 153, 158],
+    [119, 137, 141, 119, 138, 142],
+    [102, 120, 125, 100, 120, 124],
+    [82, 98, 104, 79, 98, 103],
+    [60, 76, 81, 58, 76, 81],
+    [39, 52, 58, 36, 52, 56],
+    [16, 27, 33, 14, 27, 31],
+    [5, 8, 9, 4, 7, 8],
+];
+
+const DPK_QPS_16F: [[u8; 6]; 11] = [
+    [148, 177, 171, 165, 187, 191],
+    [133, 155, 153, 147, 172, 181],
+    [114, 133, 138, 130, 157, 167],
+    [97, 118, 120, 109, 137, 144],
+    [76, 98, 103, 85, 115, 121],
+    [63, 86, 91, 62, 96, 99],
+    [46, 68, 71, 43, 73, 75],
+    [29, 48, 52, 27, 48, 51],
+    [16, 30, 35, 14, 29, 34],
+    [8, 14, 17, 7, 13, 17],
+    [3, 5, 7, 3, 5, 6],
+];
+
+const DPK_QPS_32F: [[u8; 6]; 11] = [
+    [194, 206, 209, 204, 211, 217],
+    [175, 187, 196, 186, 193, 205],
+    [157, 170, 177, 167, 180, 190],
+    [133, 152, 156, 144, 163, 168],
+    [116, 138, 142, 117, 143, 148],
+    [98, 120, 123, 96, 123, 126],
+    [80, 99, 102, 78, 99, 102],
+    [65, 79, 84, 63, 79, 84],
+    [48, 61, 67, 45, 60, 66],
+    [27, 41, 46, 24, 40, 45],
+    [3, 22, 24, 2, 21, 22],
+];
+
+///
+/// How to store the alpha channel of pixel formats that have one.
+///
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum AlphaMode {
+    /// Drop the alpha channel.
+    Discard,
+    /// Store alpha as a separate codestream after the image,
+    /// as JxrEncApp does by default.
+    Planar,
+    /// Store alpha as an extra channel within the image's own
+    /// codestream, so it can't be dropped without decoding.
+    Interleaved,
+}
+
+///
+/// Compression settings for the encoder.
+///
+/// Quantizer values (QP) run from 1 (lossless) to 255 (smallest
+/// file); values below 2 are treated as lossless. For the
+/// per-band and per-channel quantizers, 0 means to inherit:
+/// U and V default to Y, and the lowpass and highpass bands
+/// default to their channel's value.
+///
+/// Alternatively set quality, which picks all the quantizers
+/// for the pixel format the same way as JxrEncApp's -q option.
+///
+/// Overlap defaults to one level, or two below quality 0.5;
+/// any level can still be lossless. Chroma defaults to full
+/// resolution, or 4:2:0 below quality 0.5 for 8-bit RGB.
+///
+/// Setting lossless guarantees that decoding gives back the
+/// exact input, or fails to encode if the pixel format or the
+/// other options can't give that guarantee. Formats with 32 bits
+/// per channel are always rounded, shared-exponent RGBE is
+/// normalized, and half floats lose the sign of zero, so those
+/// are rejected.
+///
+/// Subband and trim_flexbits throw away detail on top of the
+/// quantizers, for small preview files. Flexbits are the low bits
+/// of the highpass coefficients left over by fine quantization;
+/// each trimmed bit halves their precision, up to 15. Higher QP
+/// leaves fewer flexbits to trim, and with 8-bit data there are
+/// none from around QP 50, so trimming matters most near lossless.
+/// Dropping the highpass or everything but DC shrinks files at
+/// any QP, and makes the quantizers for the dropped bands moot.
+///
+/// Alpha channels are stored as a separate plane by default. When
+/// encoding in bands, that plane is held aside until the image is
+/// finished; see ImageEncode::set_memory_limit. Alpha has its own
+/// quantizer, alpha_qp, which defaults to 1 (lossless) and isn't
+/// set by quality, so edges can stay exact while color is lossy.
+/// Subband and trim_flexbits don't touch a separate alpha plane
+/// either, but do apply to interleaved alpha.
+///
+/// Polarity says which way round to read 1-bit BlackWhite pixels,
+/// and is recorded in the file; see Polarity for packing helpers.
+///
+/// Orientation is recorded for viewers to turn the image by; the
+/// pixels are stored as given.
+///
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct EncoderOptions {
+    pub lossless: bool,
+    pub alpha: AlphaMode,
+    pub bitstream: BitstreamFormat,
+    pub overlap: Option<Overlap>,
+    pub chroma: Option<ChromaSubsampling>,
+    pub precision: FloatPrecision,
+    pub subband: Subband,
+    pub trim_flexbits: u8,
+    pub polarity: Polarity,
+    pub orientation: Orientation,
+    pub quality: Option<f32>,
+    pub qp: u8,
+    pub qp_y_lp: u8,
+    pub qp_y_hp: u8,
+    pub qp_u: u8,
+    pub qp_u_lp: u8,
+    pub qp_u_hp: u8,
+    pub qp_v: u8,
+    pub qp_v_lp: u8,
+    pub qp_v_hp: u8,
+    pub alpha_qp: u8,
+}
+
+impl Default for EncoderOptions {
+    fn default() -> Self {
+        Self {
+            lossless: false,
+            alpha: AlphaMode::Planar,
+            bitstream: BitstreamFormat::Spatial,
+            overlap: None,
+            chroma: None,
+            precision: FloatPrecision::default(),
+            subband: Subband::All,
+            trim_flexbits: 0,
+            polarity: Polarity::WhiteIsOne,
+            orientation: Orientation::None,
+            quality: None,
+            qp: 1,
+            qp_y_lp: 0,
+            qp_y_hp: 0,
+            qp_u: 0,
+            qp_u_lp: 0,
+            qp_u_hp: 0,
+            qp_v: 0,
+            qp_v_lp: 0,
+            qp_v_hp: 0,
+            alpha_qp: 1,
+        }
+    }
+}
+
+impl EncoderOptions {
+
+    ///
+    /// Options for the given quality from 0.0 to 1.0,
+    /// where 1.0 is lossless.
+    ///
+    pub fn with_quality(quality: f32) -> Self {
+        Self {
+            quality: Some(quality),
+            ..Default::default()
+        }
+    }
+
+    ///
+    /// Options for mathematically lossless encoding: all quantizers
+    /// at 1, all subbands kept, and a single overlap filter pass.
+    ///
+    pub fn lossless() -> Self {
+        Self {
+            lossless: true,
+            ..Default::default()
+        }
+    }
+
+    fn apply_precision(&self, info: &PixelInfo, param: &mut CWMIStrCodecParam) -> Result<()> {
+        let FloatPrecision { mantissa_or_shift, exp_bias } = self.precision;
+        match info.bit_depth() {
+            BitDepthBits::ThirtyTwoF => {
+                // A bias of -128 would be stored as 0, the default.
+                if mantissa_or_shift > 19 || exp_bias == i8::MIN {
+                    return Err(InvalidPrecision);
+                }
+            },
+            BitDepthBits::ThirtyTwo | BitDepthBits::ThirtyTwoS => {
+                if mantissa_or_shift != 0 && !(4..=31).contains(&mantissa_or_shift) {
+                    return Err(InvalidPrecision);
+                }
+                if exp_bias != 0 {
+                    return Err(PrecisionNotSupported);
+                }
+            },
+            _ => {
+                if self.precision != FloatPrecision::default() {
+                    return Err(PrecisionNotSupported);
+                }
+            }
+        }
+        param.nLenMantissaOrShift = mantissa_or_shift;
+        // The codec counts the bias from -128, with 0 meaning default.
+        param.nExpBias = if exp_bias == 0 { 0 } else { exp_bias.wrapping_add(-128) };
+        Ok(())
+    }
+
+    fn quantizers(&self) -> [u8; 9] {
+        [
+            self.qp, self.qp_y_lp, self.qp_y_hp,
+            self.qp_u, self.qp_u_lp, self.qp_u_hp,
+            self.qp_v, self.qp_v_lp, self.qp_v_hp,
+        ]
+    }
+
+    fn has_chroma_quantizers(&self) -> bool {
+        self.quantizers()[3..].iter().any(|&qp| qp != 0)
+    }
+
+    ///
+    /// Check the options against the pixel format and fill out
+    /// the codec parameters, including the quality mapping.
+    ///
+    fn apply(&self, info: &PixelInfo, width: u32, param: &mut CWMIStrCodecParam) -> Result<()> {
+        if self.has_chroma_quantizers() && info.color_format() == ColorFormat::YOnly {
+            return Err(NoChromaChannels);
+        }
+        if self.alpha_qp > 1 && (!info.has_alpha() || self.alpha == AlphaMode::Discard) {
+            return Err(NoAlphaChannel);
+        }
+        if self.lossless {
+            self.check_lossless(info)?;
+        }
+
+        // CMYK can't go through the YUV color transform.
+        param.cfColorFormat = if info.color_format() == ColorFormat::CMYK {
+            COLORFORMAT_CMYK
+        } else {
+            COLORFORMAT_YUV_444
+        };
+        match self.chroma {
+            None | Some(ChromaSubsampling::YUV444) => {},
+            Some(chroma) => {
+                // Only integer RGB goes through the color transform.
+                let float = matches!(info.bit_depth(),
+                    BitDepthBits::SixteenF | BitDepthBits::ThirtyTwoF);
+                if info.color_format() != ColorFormat::RGB || float {
+                    return Err(ChromaSubsamplingNotSupported);
+                }
+                param.cfColorFormat = if chroma == ChromaSubsampling::YUV420 {
+                    COLORFORMAT_YUV_420
+                } else {
+                    COLORFORMAT_YUV_422
+                };
+            }
+        }
+        self.apply_precision(info, param)?;
+        param.olOverlap = match (self.overlap, self.quality) {
+            (Some(overlap), _) => overlap.to_raw(),
+            // Below half quality, smooth more as JxrEncApp does.
+            (None, Some(quality)) if quality < 0.5 && width >= 32 => OVERLAP_OL_TWO,
+            (None, _) => OVERLAP_OL_ONE
+        };
+        if self.trim_flexbits > 15 {
+            return Err(InvalidFlexbitTrim);
+        }
+        if self.polarity != Polarity::WhiteIsOne && info.bit_depth() != BitDepthBits::One {
+            return Err(PolarityNotSupported);
+        }
+        param.bBlackWhite = self.polarity.to_raw();
+        param.sbSubband = self.subband.to_raw();
+        param.uiTrimFlexBits = self.trim_flexbits;
+        param.bfBitstreamFormat = self.bitstream.to_raw();
+
+        param.uAlphaMode = match self.alpha {
+            _ if !info.has_alpha() => 0,
+            AlphaMode::Discard => 0,
+            AlphaMode::Planar => 2,
+            AlphaMode::Interleaved => {
+                // The glue code can't split off alpha after N channels.
+                if info.color_format() == ColorFormat::NComponent {
+                    return Err(InterleavedAlphaNotSupported);
+                }
+                3
+            }
+        };
+        param.uiDefaultQPIndexAlpha = self.alpha_qp;
+
+        let quality = match self.quality {
+            None => {
+                param.uiDefaultQPIndex = self.qp;
+                param.uiDefaultQPIndexYLP = self.qp_y_lp;
+                param.uiDefaultQPIndexYHP = self.qp_y_hp;
+                param.uiDefaultQPIndexU = self.qp_u;
+                param.uiDefaultQPIndexULP = self.qp_u_lp;
+                param.uiDefaultQPIndexUHP = self.qp_u_hp;
+                param.uiDefaultQPIndexV = self.qp_v;
+                param.uiDefaultQPIndexVLP = self.qp_v_lp;
+                param.uiDefaultQPIndexVHP = self.qp_v_hp;
+                return Ok(());
+            },
+            Some(quality) => quality
+        };
+        if !(0.0..=1.0).contains(&quality) {
+            return Err(InvalidQuality);
+        }
+        if self.quantizers() != Self::default().quantizers() {
+            return Err(QualityWithQuantizers);
+        }
+
+        if quality >= 1.0 {
+            param.uiDefaultQPIndex = 1;
+            return Ok(());
+        }
+
+        // Below full quality, trade off chroma resolution
+        // for size, as JxrEncApp does.
+        let bit_depth = info.bit_depth();
+        if quality < 0.5 && self.chroma.is_none() && info.raw.uBitsPerSample <= 8 &&
+            info.color_format() != ColorFormat::RGBE &&
+            info.color_format() != ColorFormat::CMYK {
+            param.cfColorFormat = COLORFORMAT_YUV_420;
+        }
+
+        if bit_depth == BitDepthBits::One || bit_depth == BitDepthBits::OneAlt {
+            param.uiDefaultQPIndex = (8.0 - 5.0 * quality + 0.5) as u8;
+            return Ok(());
+        }
+
+        let subsampled = param.cfColorFormat == COLORFORMAT_YUV_420 ||
+            param.cfColorFormat == COLORFORMAT_YUV_422;
+        let mut quality = quality;
+        if quality > 0.8 && bit_depth == BitDepthBits::Eight && !subsampled {
+            // Remap [0.8, 0.866, 0.933, 1.0] to [0.8, 0.9, 1.0, 1.1]
+            // to use the extra row of the 8-bit table.
+            quality = 0.8 + (quality - 0.8) * 1.5;
+        }
+        let qi = (10.0 * quality) as usize;
+        let qf = 10.0 * quality - qi as f32;
+        let table: &[[u8; 6]] = if subsampled {
+            &DPK_QPS_420
+        } else {
+            match bit_depth {
+                BitDepthBits::Eight => &DPK_QPS_8,
+                BitDepthBits::Sixteen => &DPK_QPS_16,
+                BitDepthBits::SixteenF => &DPK_QPS_16F,
+                _ => &DPK_QPS_32F
+            }
+        };
+        let qp = |i: usize| {
+            (0.5 + table[qi][i] as f32 * (1.0 - qf) + table[qi + 1][i] as f32 * qf) as u8
+        };
+        param.uiDefaultQPIndex = qp(0);
+        param.uiDefaultQPIndexU = qp(1);
+        param.uiDefaultQPIndexV = qp(2);
+        param.uiDefaultQPIndexYHP = qp(3);
+        param.uiDefaultQPIndexUHP = qp(4);
+        param.uiDefaultQPIndexVHP = qp(5);
+        Ok(())
+    }
+
+    fn check_lossless(&self, info: &PixelInfo) -> Result<()> {
+        match info.bit_depth() {
+            BitDepthBits::ThirtyTwo |
+            BitDepthBits::ThirtyTwoS |
+            BitDepthBits::ThirtyTwoF |
+            BitDepthBits::SixteenF => return Err(LosslessNotSupported),
+            _ => {}
+        }
+        if info.color_format() == ColorFormat::RGBE {
+            return Err(LosslessNotSupported);
+        }
+        if info.has_alpha() && self.alpha == AlphaMode::Discard {
+            return Err(LosslessWithLossyOptions);
+        }
+        if matches!(self.chroma, Some(ChromaSubsampling::YUV420 | ChromaSubsampling::YUV422)) ||
+            self.subband != Subband::All || self.trim_flexbits > 0 {
+            return Err(LosslessWithLossyOptions);
+        }
+
+        // Quantizers below 2 all mean lossless, and 0 inherits.
+        let full_quality = match self.quality {
+            Some(quality) => quality >= 1.0,
+            None => true
+        };
+        if !full_quality || self.quantizers().iter().any(|&qp| qp > 1) || self.alpha_qp > 1 {
+            return Err(LosslessWithLossyOptions);
+        }
+        Ok(())
+    }
+}
+
+///
+/// Maximum number of tile columns, and of tile rows, in an image.
+///
+pub const MAX_TILES: usize = 4096;
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+enum TileSpacing {
+    Boundaries(Vec<u32>),
+    Uniform(u32),
+}
+
+impl TileSpacing {
+    ///
+    /// Internal: tile sizes in macroblocks along an edge of the
+    /// given length in pixels, the last tile taking the remainder.
+    ///
+    fn sizes(&self, pixels: u32) -> Result<Vec<u32>> {
+        let macroblocks = pixels.div_ceil(16);
+        let mut sizes = Vec::new();
+        match self {
+            TileSpacing::Boundaries(boundaries) => {
+                let mut start = 0;
+                for &boundary in boundaries {
+                    if boundary <= start || boundary >= macroblocks {
+                        return Err(InvalidTileLayout);
+                    }
+                    sizes.push(boundary - start);
+                    start = boundary;
+                }
+                sizes.push(macroblocks - start);
+            },
+            TileSpacing::Uniform(size) => {
+                if *size == 0 || size % 16 != 0 {
+                    return Err(InvalidTileLayout);
+                }
+                let size = size / 16;
+                let count = macroblocks.div_ceil(size);
+                sizes.resize(count as usize - 1, size);
+                sizes.push(macroblocks - size * (count - 1));
+            }
+        }
+        if sizes.len() > MAX_TILES {
+            return Err(TooManyTiles);
+        }
+        // Sizes are stored in 16 bits in the header.
+        if sizes.iter().any(|&size| size > 65535) {
+            return Err(InvalidTileLayout);
+        }
+        Ok(sizes)
+    }
+}
+
+///
+/// Tiling of the encoded image, so regions can be decoded
+/// without reading the whole codestream.
+///
+/// Tile boundaries are on macroblocks, 16 pixels square. Either
+/// list the macroblock column and row where each new tile starts,
+/// or give a uniform tile size in pixels (a multiple of 16) with
+/// smaller tiles at the right and bottom edges as needed.
+///
+/// With hard boundaries, the overlap filter does not cross tile
+/// edges, so each tile decodes independently of its neighbors.
+///
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct TileLayout {
+    columns: TileSpacing,
+    rows: TileSpacing,
+    hard_boundaries: bool,
+}
+
+impl Default for TileLayout {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TileLayout {
+    ///
+    /// A single tile covering the whole image.
+    ///
+    pub fn new() -> Self {
+        Self {
+            columns: TileSpacing::Boundaries(Vec::new()),
+            rows: TileSpacing::Boundaries(Vec::new()),
+            hard_boundaries: false
+        }
+    }
+
+    ///
+    /// Tiles of the given size in pixels, which must be
+    /// multiples of 16.
+    ///
+    pub fn uniform(width: u32, height: u32) -> Self {
+        Self::new()
+            .column_size(width)
+            .row_size(height)
+    }
+
+    ///
+    /// Start a new column of tiles at each of these macroblock
+    /// offsets, in increasing order, after the one at 0.
+    ///
+    pub fn column_boundaries(mut self, boundaries: &[u32]) -> Self {
+        self.columns = TileSpacing::Boundaries(boundaries.to_vec());
+        self
+    }
+
+    ///
+    /// Start a new row of tiles at each of these macroblock
+    /// offsets, in increasing order, after the one at 0.
+    ///
+    pub fn row_boundaries(mut self, boundaries: &[u32]) -> Self {
+        self.rows = TileSpacing::Boundaries(boundaries.to_vec());
+        self
+    }
+
+    ///
+    /// Make tile columns this many pixels wide.
+    ///
+    pub fn column_size(mut self, width: u32) -> Self {
+        self.columns = TileSpacing::Uniform(width);
+        self
+    }
+
+    ///
+    /// Make tile rows this many pixels high.
+    ///
+    pub fn row_size(mut self, height: u32) -> Self {
+        self.rows = TileSpacing::Uniform(height);
+        self
+    }
+
+    ///
+    /// Keep the overlap filter from crossing tile boundaries.
+    ///
+    pub fn hard_boundaries(mut self, hard: bool) -> Self {
+        self.hard_boundaries = hard;
+        self
+    }
+
+    ///
+    /// Internal: check the layout against the image size and
+    /// fill in the codec parameters.
+    ///
+    fn apply(&self, width: u32, height: u32, param: &mut CWMIStrCodecParam) -> Result<()> {
+        let columns = self.columns.sizes(width)?;
+        let rows = self.rows.sizes(height)?;
+
+        // The codec takes the sizes of all but the last tile.
+        param.cNumOfSliceMinus1V = columns.len() as u32 - 1;
+        param.cNumOfSliceMinus1H = rows.len() as u32 - 1;
+        param.uiTileX = [0; MAX_TILES];
+        param.uiTileY = [0; MAX_TILES];
+        param.uiTileX[..columns.len() - 1].copy_from_slice(&columns[..columns.len() - 1]);
+        param.uiTileY[..rows.len() - 1].copy_from_slice(&rows[..rows.len() - 1]);
+        param.bUseHardTileBoundaries = self.hard_boundaries as Bool;
+        Ok(())
+    }
+}
+
+///
+/// Size to aim for when searching for a quantizer.
+///
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum RateTarget {
+    /// Total file size in bytes, including the container.
+    Bytes(usize),
+    /// Total file size in bits, divided by the pixel count.
+    BitsPerPixel(f32),
+}
+
+///
+/// Outcome of a rate-controlled encode.
+///
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct RateControl {
+    /// Number of trial encodes made during the search.
+    pub attempts: usize,
+    /// Quantizer the file was encoded with.
+    pub qp: u8,
+    /// Size of the file in bytes.
+    pub bytes: usize,
+}
+
+///
+/// High-level JPEG XR image encoder struct.
+/// Requires a seekable data sink, such as a File, as the container
+/// header is patched up after the pixel data is written.
+/// The output must start at position 0 in the writer.
+/// Wrap other outputs in a SpooledWriter.
+///
+pub struct ImageEncode<W: Write + Seek> {
+    raw: *mut PKImageEncode,
+    stream: Option<OutputStream<W>>,
+    options: EncoderOptions,
+    tiles: TileLayout,
+    memory_limit: usize,
+}
+
+///
+/// Default size up to which temporary data is kept in memory.
+///
+pub const DEFAULT_MEMORY_LIMIT: usize = 64 * 1024 * 1024;
+
+impl<W> ImageEncode<W> where W: Write + Seek {
+
+    ///
+    /// Create a new JPEG XR image encoder for the given output.
+    /// This will consume the writer; get it back with into_writer().
+    /// The output is written in small pieces, so wrap files
+    /// in a BufWriter.
+    ///
+    pub fn with_writer(writer: W) -> Result<Self> {
+        unsafe {
+            let mut stream = OutputStream::new(writer);
+            let mut param = Self::default_param();
+
+            let mut codec: *mut PKImageEncode = std::ptr::null_mut();
+            call(PKImageEncode_Create_WMP(&mut codec as *mut *mut PKImageEncode))?;
+            call((*codec).Initialize.unwrap()(
+                codec,
+                stream.raw.as_mut(),
+                &mut param as *mut CWMIStrCodecParam as *mut c_void,
+                std::mem::size_of::<CWMIStrCodecParam>()
+            ))?;
+
+            // The WMP initializer skips the generic defaults.
+            (*codec).fResX = 96.0;
+            (*codec).fResY = 96.0;
+            (*codec).cFrame = 1;
+
+            Ok(Self {
+                raw: codec,
+                stream: Some(stream),
+                options: Default::default(),
+                tiles: TileLayout::new(),
+                memory_limit: DEFAULT_MEMORY_LIMIT
+            })
+        }
+    }
+
+    ///
+    /// Internal: codec parameters before the options are applied.
+    /// Same defaults as the JxrEncApp sample tool: lossless,
+    /// full-resolution chroma, one level of overlap filtering.
+    ///
+    fn default_param() -> CWMIStrCodecParam {
+        let mut param: CWMIStrCodecParam = unsafe { std::mem::zeroed() };
+        param.cfColorFormat = COLORFORMAT_YUV_444;
+        param.bdBitDepth = BITDEPTH_BD_LONG;
+        param.olOverlap = OVERLAP_OL_ONE;
+        param.bfBitstreamFormat = BITSTREAMFORMAT_SPATIAL;
+        param.sbSubband = SUBBAND_SB_ALL;
+        param.uiDefaultQPIndex = 1;
+        param.uiDefaultQPIndexAlpha = 1;
+        param
+    }
+
+    ///
+    /// Set the pixel format of the input data, which is also
+    /// the pixel format recorded in the file.
+    ///
+    pub fn set_pixel_format(&mut self, format: PixelFormat) -> Result<()> {
+        unsafe {
+            call((*self.raw).SetPixelFormat.unwrap()(self.raw, *format.guid()))
+        }
+    }
+
+    ///
+    /// Set width and height in pixels.
+    ///
+    pub fn set_size(&mut self, width: i32, height: i32) -> Result<()> {
+        unsafe {
+            call((*self.raw).SetSize.unwrap()(self.raw, width, height))
+        }
+    }
+
+    ///
+    /// Set horizontal and vertical DPI.
+    ///
+    pub fn set_resolution(&mut self, horiz: f32, vert: f32) -> Result<()> {
+        if !(horiz.is_finite() && horiz > 0.0 && vert.is_finite() && vert > 0.0) {
+            return Err(InvalidResolution);
+        }
+        unsafe {
+            // Written with the header, so would be silently dropped.
+            if (*self.raw).fHeaderDone != 0 {
+                return Err(OutOfSequence);
+            }
+            call((*self.raw).SetResolution.unwrap()(self.raw, horiz, vert))
+        }
+    }
+
+    ///
+    /// Embed an ICC color profile.
+    ///
+    pub fn set_icc_profile(&mut self, profile: &[u8]) -> Result<()> {
+        unsafe {
+            self.set_blob((*self.raw).SetColorContext.unwrap(), profile)
+        }
+    }
+
+    ///
+    /// Embed an XMP packet. Any dc:format in it is replaced
+    /// with the JPEG XR media type.
+    ///
+    pub fn set_xmp_metadata(&mut self, xmp: &[u8]) -> Result<()> {
+        self.set_blob(PKImageEncode_SetXMPMetadata_WMP, xmp)
+    }
+
+    ///
+    /// Embed an EXIF IFD. This is a little-endian TIFF directory
+    /// starting at offset 0 of the data, with any value offsets
+    /// relative to that; they are relocated when written.
+    ///
+    pub fn set_exif_metadata(&mut self, ifd: &[u8]) -> Result<()> {
+        self.set_blob(PKImageEncode_SetEXIFMetadata_WMP, ifd)
+    }
+
+    ///
+    /// Embed a GPS info IFD, laid out the same way as for EXIF.
+    ///
+    pub fn set_gps_metadata(&mut self, ifd: &[u8]) -> Result<()> {
+        self.set_blob(PKImageEncode_SetGPSInfoMetadata_WMP, ifd)
+    }
+
+    ///
+    /// Embed an IPTC-NAA record.
+    ///
+    pub fn set_iptc_metadata(&mut self, iptc: &[u8]) -> Result<()> {
+        self.set_blob(PKImageEncode_SetIPTCNAAMetadata_WMP, iptc)
+    }
+
+    ///
+    /// Embed Photoshop image resources.
+    ///
+    pub fn set_photoshop_metadata(&mut self, resources: &[u8]) -> Result<()> {
+        self.set_blob(PKImageEncode_SetPhotoshopMetadata_WMP, resources)
+    }
+
+    ///
+    /// Set descriptive metadata such as the title and artist.
+    ///
+    pub fn set_descriptive_metadata(&mut self, metadata: &DescriptiveMetadata) -> Result<()> {
+        let mut strings = Vec::new();
+        let mut caption = Vec::new();
+        let raw = metadata.to_raw(&mut strings, &mut caption)?;
+        unsafe {
+            // Checked here too, as the codec asserts on it.
+            if (*self.raw).fHeaderDone != 0 {
+                return Err(OutOfSequence);
+            }
+            call((*self.raw).SetDescriptiveMetadata.unwrap()(self.raw, &raw))
+        }
+    }
+
+    ///
+    /// Internal: hand a metadata block to the codec, which copies
+    /// it to write out with the container header.
+    ///
+    fn set_blob(&mut self, setter: unsafe extern "C" fn(*mut PKImageEncode, *const U8, U32) -> ERR, data: &[u8]) -> Result<()> {
+        let len = u32::try_from(data.len())?;
+        unsafe {
+            // Checked here too, as the codec asserts on it.
+            if (*self.raw).fHeaderDone != 0 {
+                return Err(OutOfSequence);
+            }
+            call(setter(self.raw, data.as_ptr(), len))
+        }
+    }
+
+    ///
+    /// Set compression options. These are checked against the
+    /// pixel format when the image is written.
+    ///
+    pub fn set_options(&mut self, options: &EncoderOptions) {
+        self.options = *options;
+    }
+
+    ///
+    /// Set the tile layout. This is checked against the image
+    /// size when the image is written.
+    ///
+    pub fn set_tile_layout(&mut self, tiles: &TileLayout) {
+        self.tiles = tiles.clone();
+    }
+
+    ///
+    /// Encode the entire image from the given pixel data, in the
+    /// pixel format and size set earlier. Rows are stride bytes apart.
+    /// This can only be done once per frame.
+    ///
+    pub fn write_pixels(&mut self, pixels: &[u8], stride: usize) -> Result<()> {
+        let stride_u32 = u32::try_from(stride)?;
+        self.check_unwritten()?;
+        unsafe {
+            let (width, height) = ((*self.raw).uWidth, (*self.raw).uHeight);
+            let info = PixelInfo::from_guid(&(*self.raw).guidPixFormat)?;
+            let (row_bytes, rows) = info.buffer_rows(width as usize, height as usize);
+            check_rows(pixels.len(), stride, rows, row_bytes)?;
+            self.prepare()?;
+            // The C API takes a mutable pointer, but only reads from it.
+            let ptr = pixels.as_ptr() as *mut u8;
+            call((*self.raw).WritePixels.unwrap()(self.raw, height, ptr, stride_u32))
+        }
+    }
+
+    ///
+    /// Encode the entire image like write_pixels, from pixel data
+    /// in another format, which is converted to the pixel format
+    /// set earlier. See PixelFormat::conversions for what's possible.
+    ///
+    pub fn write_pixels_from(&mut self, format: PixelFormat, pixels: &[u8], stride: usize) -> Result<()> {
+        let (target, width, height) = unsafe {
+            (PixelFormat::from_guid(&(*self.raw).guidPixFormat)?, (*self.raw).uWidth, (*self.raw).uHeight)
+        };
+        if format == target {
+            return self.write_pixels(pixels, stride);
+        }
+        self.check_unwritten()?;
+        let mut source = PixelSource::new(format, width, height, pixels, stride)?;
+        unsafe {
+            let mut converter: *mut PKFormatConverter = std::ptr::null_mut();
+            call(PKCodecFactory_CreateFormatConverter(&mut converter))?;
+            let result = match call((*converter).Initialize.unwrap()(
+                converter,
+                &mut source.raw,
+                std::ptr::null_mut(),
+                *target.guid()
+            )) {
+                Err(UnsupportedFormat) => Err(NoConversion {
+                    from: format,
+                    to: target,
+                    possible: format.conversions()
+                }),
+                Err(e) => Err(e),
+                Ok(()) => self.prepare().and_then(|_| {
+                    let mut rect = Rect::new(0, 0, width as i32, height as i32);
+                    call(PKImageEncode_WriteSource(self.raw, converter, &mut rect.raw))
+                })
+            };
+            (*converter).Release.unwrap()(&mut converter);
+            result
+        }
+    }
+
+    ///
+    /// Encode the entire image like write_pixels, picking the
+    /// smallest quantizer that keeps the file within the target
+    /// size. The search stops early once a trial lands within
+    /// tolerance (a fraction, such as 0.05) under the target.
+    ///
+    /// Trial encodes are made in memory from the same pixels,
+    /// with the resolution, metadata and other options set so
+    /// far; the options must not set quality, quantizers or
+    /// lossless, as the search picks them. The chosen quantizer
+    /// is only used for this frame, and the options are left as
+    /// they were. In a file with several frames, the target is
+    /// the size this frame would have as a file of its own.
+    ///
+    pub fn write_pixels_to_target(&mut self, pixels: &[u8], stride: usize, target: RateTarget, tolerance: f32) -> Result<RateControl> {
+        let (format, width, height) = unsafe {
+            (PixelFormat::from_guid(&(*self.raw).guidPixFormat)?, (*self.raw).uWidth, (*self.raw).uHeight)
+        };
+        let max_bytes = match target {
+            RateTarget::Bytes(bytes) => bytes as f64,
+            RateTarget::BitsPerPixel(bpp) => bpp as f64 * width as f64 * height as f64 / 8.0,
+        };
+        if !(max_bytes > 0.0 && (0.0..1.0).contains(&tolerance)) {
+            return Err(InvalidRateTarget);
+        }
+        if self.options.lossless {
+            return Err(LosslessWithLossyOptions);
+        }
+        if self.options.quality.is_some() || self.options.quantizers() != EncoderOptions::default().quantizers() {
+            return Err(QualityWithQuantizers);
+        }
+        self.check_unwritten()?;
+        let (row_bytes, rows) = PixelInfo::from_format(format).buffer_rows(width as usize, height as usize);
+        check_rows(pixels.len(), stride, rows, row_bytes)?;
+
+        // File size shrinks as the quantizer grows, so bisect.
+        let mut attempts = 0;
+        let mut best = None;
+        let (mut low, mut high) = (1u32, 255u32);
+        while low <= high {
+            let mid = (low + high) / 2;
+            let qp = mid as u8;
+            let mut trial = ImageEncode::with_writer(Cursor::new(Vec::new()))?;
+            trial.set_pixel_format(format)?;
+            trial.set_size(width as i32, height as i32)?;
+            trial.set_options(&EncoderOptions { qp, ..self.options });
+            trial.set_tile_layout(&self.tiles);
+            self.copy_header(&mut trial)?;
+            trial.write_pixels(pixels, stride)?;
+            let bytes = trial.into_writer().into_inner().len();
+            attempts += 1;
+
+            if bytes as f64 <= max_bytes {
+                best = Some(RateControl { attempts: 0, qp, bytes });
+                if bytes as f64 >= max_bytes * (1.0 - tolerance as f64) {
+                    break;
+                }
+                high = mid - 1;
+            } else {
+                low = mid + 1;
+            }
+        }
+        let best = best.ok_or(TargetSizeUnreachable)?;
+
+        // Encoding is deterministic and the header is the same,
+        // so this matches the trial.
+        let qp = self.options.qp;
+        self.options.qp = best.qp;
+        let result = self.write_pixels(pixels, stride);
+        self.options.qp = qp;
+        result?;
+        Ok(RateControl { attempts, ..best })
+    }
+
+    ///
+    /// Internal: give another encoder the resolution and metadata
+    /// set on this one, which go in the container header.
+    ///
+    fn copy_header<T: Write + Seek>(&self, to: &mut ImageEncode<T>) -> Result<()> {
+        unsafe {
+            let raw = &*self.raw;
+            call((*to.raw).SetResolution.unwrap()(to.raw, raw.fResX, raw.fResY))?;
+            let blobs = [
+                ((*to.raw).SetColorContext.unwrap(), raw.pbColorContext, raw.cbColorContext),
+                (PKImageEncode_SetXMPMetadata_WMP, raw.pbXMPMetadata, raw.cbXMPMetadataByteCount),
+                (PKImageEncode_SetEXIFMetadata_WMP, raw.pbEXIFMetadata, raw.cbEXIFMetadataByteCount),
+                (PKImageEncode_SetGPSInfoMetadata_WMP, raw.pbGPSInfoMetadata, raw.cbGPSInfoMetadataByteCount),
+                (PKImageEncode_SetIPTCNAAMetadata_WMP, raw.pbIPTCNAAMetadata, raw.cbIPTCNAAMetadataByteCount),
+                (PKImageEncode_SetPhotoshopMetadata_WMP, raw.pbPhotoshopMetadata, raw.cbPhotoshopMetadataByteCount),
+            ];
+            for (setter, data, len) in blobs {
+                if !data.is_null() {
+                    to.set_blob(setter, std::slice::from_raw_parts(data, len as usize))?;
+                }
+            }
+            call((*to.raw).SetDescriptiveMetadata.unwrap()(to.raw, &raw.sDescMetadata))
+        }
+    }
+
+    ///
+    /// Finish the current frame and start another in the same file,
+    /// as for the pages of a scanned document. Size, pixel format,
+    /// resolution, options and tile layout carry over, and can be
+    /// changed before writing the new frame's pixels; metadata
+    /// starts out empty.
+    ///
+    pub fn new_frame(&mut self) -> Result<()> {
+        let mut param = Self::default_param();
+        unsafe {
+            call((*self.raw).CreateNewFrame.unwrap()(
+                self.raw,
+                &mut param as *mut CWMIStrCodecParam as *mut c_void,
+                std::mem::size_of::<CWMIStrCodecParam>()
+            ))
+        }
+    }
+
+    ///
+    /// Set the size in bytes up to which temporary data, such as
+    /// planar alpha during banded encoding, is kept in memory.
+    /// Anything larger goes to an anonymous temporary file.
+    ///
+    pub fn set_memory_limit(&mut self, bytes: usize) {
+        self.memory_limit = bytes;
+    }
+
+    ///
+    /// Switch to encoding the image a band of rows at a time,
+    /// for images too large to hold in memory at once. The pixel
+    /// format, size and options must be set first.
+    ///
+    pub fn into_banded(mut self) -> Result<BandedEncoder<W>> {
+        self.check_unwritten()?;
+        let info = self.prepare()?;
+        let (width, height) = unsafe { ((*self.raw).uWidth as usize, (*self.raw).uHeight as usize) };
+        let row_bytes = (width * info.bits_per_pixel()).div_ceil(8);
+
+        // Planar alpha is written to the side, and copied in at the end.
+        let mut alpha_stream = None;
+        if unsafe { (*self.raw).WMP.wmiSCP.uAlphaMode } == 2 {
+            let size = width * height * info.raw.uBitsPerSample as usize / 8;
+            alpha_stream = Some(TempStream::new(TempBuffer::with_size_hint(size, self.memory_limit)?));
+        }
+        let alpha_raw = match alpha_stream.as_mut() {
+            Some(stream) => stream.raw.as_mut() as *mut WMPStream,
+            None => std::ptr::null_mut()
+        };
+        unsafe {
+            call((*self.raw).WritePixelsBandedBegin.unwrap()(self.raw, alpha_raw))?;
+        }
+        Ok(BandedEncoder {
+            encoder: self,
+            alpha_stream,
+            row_bytes,
+            buffer: Vec::with_capacity(row_bytes * 16),
+            rows_written: 0,
+        })
+    }
+
+    ///
+    /// Internal: check that the current frame's pixels haven't been
+    /// written yet, as the codec asserts on it.
+    ///
+    fn check_unwritten(&self) -> Result<()> {
+        if unsafe { (*self.raw).WMP.eBandedEncState } != BANDEDENCSTATE_BANDEDENCSTATE_UNINITIALIZED {
+            return Err(OutOfSequence);
+        }
+        Ok(())
+    }
+
+    ///
+    /// Internal: check the options against the pixel format
+    /// and set up the codec parameters, just before encoding.
+    ///
+    fn prepare(&mut self) -> Result<PixelInfo> {
+        unsafe {
+            let info = PixelInfo::from_guid(&(*self.raw).guidPixFormat)?;
+            let wmp = &mut (*self.raw).WMP;
+            self.options.apply(&info, (*self.raw).uWidth, &mut wmp.wmiSCP)?;
+            self.tiles.apply((*self.raw).uWidth, (*self.raw).uHeight, &mut wmp.wmiSCP)?;
+            wmp.oOrientation = self.options.orientation.to_raw();
+
+            // Planar alpha is coded as a separate grayscale image, with
+            // its own copy of the parameters taken when we were created.
+            let alpha_stream = wmp.wmiSCP_Alpha.pWStream;
+            wmp.wmiSCP_Alpha = wmp.wmiSCP;
+            wmp.wmiSCP_Alpha.pWStream = alpha_stream;
+            wmp.wmiSCP_Alpha.uAlphaMode = 0;
+            wmp.wmiSCP_Alpha.uiDefaultQPIndex = self.options.alpha_qp;
+            wmp.wmiSCP_Alpha.uiDefaultQPIndexYLP = 0;
+            wmp.wmiSCP_Alpha.uiDefaultQPIndexYHP = 0;
+            // Only alpha_qp makes planar alpha lossy.
+            wmp.wmiSCP_Alpha.sbSubband = SUBBAND_SB_ALL;
+            wmp.wmiSCP_Alpha.uiTrimFlexBits = 0;
+            Ok(info)
+        }
+    }
+
+    ///
+    /// Free the image encoder and return the output writer.
+    ///
+    pub fn into_writer(mut self) -> W {
+        let mut stream: Option<OutputStream<W>> = None;
+        std::mem::swap(&mut stream, &mut self.stream);
+        stream.unwrap().into_writer()
+    }
+}
+
+impl<W> Drop for ImageEncode<W> where W: Write + Seek {
+    fn drop(&mut self) {
+        unsafe {
+            // Release the C structure.
+            (*self.raw).Release.unwrap()(&mut self.raw);
+        }
+    }
+}
+
+///
+/// Encoder that takes the image in bands of rows, which may
+/// be any height. The codec works on 16-row macroblock strips,
+/// so short bands are collected here until there's enough.
+/// Get one with ImageEncode::into_banded().
+///
+pub struct BandedEncoder<W: Write + Seek> {
+    encoder: ImageEncode<W>,
+    alpha_stream: Option<TempStream>,
+    row_bytes: usize,
+    buffer: Vec<u8>,
+    rows_written: usize,
+}
+
+impl<W> BandedEncoder<W> where W: Write + Seek {
+
+    ///
+    /// Encode the next rows of the image from the given pixel
+    /// data, with rows stride bytes apart.
+    ///
+    pub fn write_rows(&mut self, pixels: &[u8], stride: usize, rows: usize) -> Result<()> {
+        if rows == 0 {
+            return Ok(());
+        }
+        check_rows(pixels.len(), stride, rows, self.row_bytes)?;
+        let height = unsafe { (*self.encoder.raw).uHeight } as usize;
+        if self.rows_written + rows > height {
+            return Err(RowCountMismatch);
+        }
+        self.rows_written += rows;
+
+        let mut row = 0;
+
+        // Top up any partial strip left over from last time.
+        if !self.buffer.is_empty() {
+            while row < rows && self.buffered_rows() < 16 {
+                self.buffer_row(&pixels[row * stride..]);
+                row += 1;
+            }
+            if self.buffered_rows() < 16 {
+                return Ok(());
+            }
+            self.flush(false)?;
+        }
+
+        // Whole strips can go straight from the caller's buffer.
+        let direct = (rows - row) / 16 * 16;
+        if direct > 0 {
+            self.write_band(&pixels[row * stride..], stride, direct, false)?;
+            row += direct;
+        }
+
+        while row < rows {
+            self.buffer_row(&pixels[row * stride..]);
+            row += 1;
+        }
+        Ok(())
+    }
+
+    ///
+    /// Finish encoding once all rows have been written,
+    /// and return the output writer.
+    ///
+    pub fn finish(self) -> Result<W> {
+        Ok(self.finish_frame()?.into_writer())
+    }
+
+    ///
+    /// Finish the image like finish, but hand back the encoder
+    /// to add another frame with ImageEncode::new_frame.
+    ///
+    pub fn finish_frame(mut self) -> Result<ImageEncode<W>> {
+        let height = unsafe { (*self.encoder.raw).uHeight } as usize;
+        if self.rows_written != height {
+            return Err(RowCountMismatch);
+        }
+        if !self.buffer.is_empty() {
+            self.flush(true)?;
+        }
+        unsafe {
+            call((*self.encoder.raw).WritePixelsBandedEnd.unwrap()(self.encoder.raw))?;
+            // The temp stream goes away here; don't leave the codec pointing at it.
+            (*self.encoder.raw).WMP.pPATempFile = std::ptr::null_mut();
+        }
+        drop(self.alpha_stream);
+        Ok(self.encoder)
+    }
+
+    fn buffered_rows(&self) -> usize {
+        self.buffer.len() / self.row_bytes
+    }
+
+    fn buffer_row(&mut self, row: &[u8]) {
+        self.buffer.extend_from_slice(&row[..self.row_bytes]);
+    }
+
+    fn flush(&mut self, last: bool) -> Result<()> {
+        let buffer = std::mem::take(&mut self.buffer);
+        let rows = buffer.len() / self.row_bytes;
+        let result = self.write_band(&buffer, self.row_bytes, rows, last);
+        self.buffer = buffer;
+        self.buffer.clear();
+        result
+    }
+
+    fn write_band(&mut self, pixels: &[u8], stride: usize, rows: usize, last: bool) -> Result<()> {
+        let stride_u32 = u32::try_from(stride)?;
+        let rows_u32 = u32::try_from(rows)?;
+        let raw = self.encoder.raw;
+        unsafe {
+            // The C API takes a mutable pointer, but only reads from it.
+            let ptr = pixels.as_ptr() as *mut u8;
+            call((*raw).WritePixelsBanded.unwrap()(raw, rows_u32, ptr, stride_u32, last as Bool))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{File};
+    use std::io::Cursor;
+    use crate::ImageDecode;
+    use crate::ImageEncode;
+    use crate::EncoderOptions;
+    use crate::AlphaMode;
+    use crate::TileLayout;
+    use crate::BitstreamFormat;
+    use crate::Overlap;
+    use crate::ChromaSubsampling;
+    use crate::RateTarget;
+    use crate::FloatPrecision;
+    use crate::Subband;
+    use crate::Polarity;
+    use crate::DescriptiveMetadata;
+    use crate::DecodeOptions;
+    use crate::Orientation;
+    use crate::Rect;
+    use crate::SpooledWriter;
+    use crate::DEFAULT_MEMORY_LIMIT;
+    use crate::JXRError;
+    use crate::PixelFormat;
+    use crate::PixelFormat::*;
+    use crate::PixelInfo;
+    use crate::ColorFormat;
+    use crate::BitDepthBits;
+    use crate::PhotometricInterpretation;
+
+    #[test]
+    fn it_works() {
+        let input_result = File::open("samples/panel-hdr.jxr");
+        assert!(input_result.is_ok());
+        let input = input_result.unwrap();
+
+        let decoder_result = ImageDecode::with_reader(input);
+        assert!(decoder_result.is_ok());
+        let decoder = decoder_result.unwrap();
+
+        let size_result = decoder.get_size();
+        assert!(size_result.is_ok());
+        let (width, height) = size_result.unwrap();
+        assert_eq!(width, 3440);
+        assert_eq!(height, 1440);
+
+        let pixfmt_result = decoder.get_pixel_format();
+        assert!(pixfmt_result.is_ok());
+        let pixfmt = pixfmt_result.unwrap();
+        assert_eq!(pixfmt, PixelFormat128bppRGBAFloat);
+
+        let info = PixelInfo::from_format(pixfmt);
+        assert_eq!(info.channels(), 4);
+        assert_eq!(info.color_format(), ColorFormat::RGB);
+        assert_eq!(info.bit_depth(), BitDepthBits::ThirtyTwoF);
+        assert!(info.has_alpha());
+        assert!(!info.premultiplied_alpha());
+        assert!(!info.bgr());
+        assert_eq!(info.photometric_interpretation(), PhotometricInterpretation::RGB);
+        assert_eq!(info.samples_per_pixel(), 4);
+    }
+
+    fn new_encoder(format: PixelFormat, width: i32, height: i32) -> ImageEncode<Cursor<Vec<u8>>> {
+        let mut encoder = ImageEncode::with_writer(Cursor::new(Vec::new())).unwrap();
+        encoder.set_pixel_format(format).unwrap();
+        encoder.set_size(width, height).unwrap();
+        encoder
+    }
+
+    fn decode_all(decoder: &mut ImageDecode<Cursor<Vec<u8>>>) -> Vec<u8> {
+        let format = decoder.get_pixel_format().unwrap();
+        let (width, height) = decoder.get_size().unwrap();
+        let (stride, rows) = PixelInfo::from_format(format).buffer_rows(width as usize, height as usize);
+        let mut output = vec![0u8; stride * rows];
+        decoder.copy_all(&mut output, stride).unwrap();
+        output
+    }
+
+    fn decode(bytes: Vec<u8>) -> Vec<u8> {
+        decode_all(&mut ImageDecode::with_reader(Cursor::new(bytes)).unwrap())
+    }
+
+    fn test_pixels(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    fn stride(format: PixelFormat, width: i32) -> usize {
+        PixelInfo::from_format(format).buffer_rows(width as usize, 1).0
+    }
+
+    fn encode(format: PixelFormat, width: i32, height: i32, pixels: &[u8], options: &EncoderOptions) -> Result<Vec<u8>, JXRError> {
+        let mut encoder = new_encoder(format, width, height);
+        encoder.set_options(options);
+        encoder.write_pixels(pixels, stride(format, width))?;
+        Ok(encoder.into_writer().into_inner())
+    }
+
+    fn encode_rgb(width: i32, height: i32, pixels: &[u8], options: &EncoderOptions) -> Result<Vec<u8>, JXRError> {
+        encode(PixelFormat24bppRGB, width, height, pixels, options)
+    }
+
+    fn noise_pixels(len: usize) -> Vec<u8> {
+        // Noise is the hardest content to code, so it needs the most bits.
+        let mut state = 0x2545f491u32;
+        (0..len).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            (state >> 24) as u8
+        }).collect()
+    }
+
+    #[test]
+    fn encode_round_trip() {
+        let (width, height) = (40, 20);
+        let stride = width as usize * 3;
+        let pixels = test_pixels(stride * height as usize);
+        let bytes = encode_rgb(width, height, &pixels, &Default::default()).unwrap();
+
+        let mut decoder = ImageDecode::with_reader(Cursor::new(bytes)).unwrap();
+        assert_eq!(decoder.get_size().unwrap(), (width, height));
+        assert_eq!(decoder.get_pixel_format().unwrap(), PixelFormat24bppRGB);
+        assert_eq!(decode_all(&mut decoder), pixels);
+
+        // Short rows and sizes past the end of memory are caught,
+        // as is writing the frame twice.
+        let mut encoder = new_encoder(PixelFormat24bppRGB, width, height);
+        assert!(matches!(encoder.write_pixels(&pixels, stride - 3), Err(JXRError::BufferTooSmall)));
+        assert!(matches!(encoder.write_pixels(&pixels, u32::MAX as usize), Err(JXRError::BufferTooSmall)));
+        encoder.write_pixels(&pixels, stride).unwrap();
+        assert!(matches!(encoder.write_pixels(&pixels, stride), Err(JXRError::OutOfSequence)));
+    }
+
+    #[test]
+    fn encode_quality() {
+        let (width, height) = (64, 48);
+        let pixels: Vec<u8> = (0..width * height * 3).map(|i| ((i % 192) + (i / 192)) as u8).collect();
+        let lossless = encode_rgb(width, height, &pixels, &Default::default()).unwrap();
+        let lossy = encode_rgb(width, height, &pixels, &EncoderOptions::with_quality(0.6)).unwrap();
+        assert!(lossy.len() < lossless.len());
+
+        assert!(matches!(encode_rgb(width, height, &pixels, &EncoderOptions::with_quality(1.5)),
+            Err(JXRError::InvalidQuality)));
+        let conflicting = EncoderOptions {
+            qp_u: 20,
+            ..EncoderOptions::with_quality(0.6)
+        };
+        assert!(matches!(encode_rgb(width, height, &pixels, &conflicting),
+            Err(JXRError::QualityWithQuantizers)));
+    }
+
+    #[test]
+    fn encode_noise() {
+        // A macroblock of 16-bit noise in seven or eight channels
+        // doesn't fit in one of the codec's 4kB bit I/O packets.
+        let (width, height) = (40, 20);
+        for format in [PixelFormat112bpp7Channels, PixelFormat128bpp8Channels] {
+            let stride = width as usize * PixelInfo::from_format(format).bits_per_pixel() / 8;
+            let pixels = noise_pixels(stride * height as usize);
+            let mut encoder = new_encoder(format, width, height);
+            encoder.write_pixels(&pixels, stride).unwrap();
+            assert!(decode(encoder.into_writer().into_inner()) == pixels, "{:?} is not lossless", format);
+        }
+    }
+
+    #[test]
+    fn decode_odd_size_ycc() {
+        // Odd sizes leave the last units partly padding, which
+        // needn't come back; everything else should.
+        for (format, unit_width, unit_height) in [(PixelFormat12bppYCC420, 2, 2), (PixelFormat16bppYCC422, 2, 1)] {
+            for (width, height) in [(41, 20), (40, 21), (41, 21)] {
+                let (stride, rows) = PixelInfo::from_format(format).buffer_rows(width, height);
+                let unit_bytes = unit_width * unit_height + 2;
+                let pixels = noise_pixels(stride * rows);
+                let mut encoder = new_encoder(format, width as i32, height as i32);
+                encoder.write_pixels(&pixels, stride).unwrap();
+                let output = decode(encoder.into_writer().into_inner());
+
+                for (i, (a, b)) in output.iter().zip(&pixels).enumerate() {
+                    let (unit, byte) = (i % stride / unit_bytes, i % stride % unit_bytes);
+                    // Y0 Y1 Y2 Y3 U V, or U Y0 V Y1.
+                    let luma = if unit_height == 2 { byte < 4 } else { byte % 2 == 1 };
+                    let index = if unit_height == 2 { byte } else { byte / 2 };
+                    let x = unit * unit_width + index % unit_width;
+                    let y = i / stride * unit_height + index / unit_width;
+                    if !luma || (x < width && y < height) {
+                        assert_eq!(a, b, "{:?} {}x{} at byte {}", format, width, height, i);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn encode_lossless() {
+        // Masks cover one pixel's bytes; padding bits aren't stored.
+        let formats: &[(PixelFormat, &[u8])] = &[
+            (PixelFormatBlackWhite, &[]),
+            (PixelFormat8bppGray, &[]),
+            (PixelFormat16bppGray, &[]),
+            (PixelFormat16bppGrayFixedPoint, &[]),
+            (PixelFormat16bppRGB555, &[0xff, 0x7f]),
+            (PixelFormat16bppRGB565, &[]),
+            (PixelFormat24bppRGB, &[]),
+            (PixelFormat24bppBGR, &[]),
+            (PixelFormat32bppRGB, &[0xff, 0xff, 0xff, 0]),
+            (PixelFormat32bppBGR, &[0xff, 0xff, 0xff, 0]),
+            (PixelFormat32bppRGBA, &[]),
+            (PixelFormat32bppBGRA, &[]),
+            (PixelFormat32bppPRGBA, &[]),
+            (PixelFormat32bppPBGRA, &[]),
+            (PixelFormat32bppRGB101010, &[0xff, 0xff, 0xff, 0x3f]),
+            (PixelFormat48bppRGB, &[]),
+            (PixelFormat48bppRGBFixedPoint, &[]),
+            (PixelFormat64bppRGBA, &[]),
+            (PixelFormat64bppPRGBA, &[]),
+            (PixelFormat64bppRGBFixedPoint, &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0, 0]),
+            (PixelFormat64bppRGBAFixedPoint, &[]),
+            (PixelFormat12bppYCC420, &[]),
+            (PixelFormat16bppYCC422, &[]),
+            (PixelFormat24bppYCC444, &[]),
+            (PixelFormat32bpp, &[]), // CMYK
+            (PixelFormat64bppCMYK, &[]),
+            (PixelFormat40bppCMYKAlpha, &[]),
+            (PixelFormat80bppCMYKAlpha, &[]),
+            (PixelFormat24bpp3Channels, &[]),
+            (PixelFormat32bpp4Channels, &[]),
+            (PixelFormat40bpp5Channels, &[]),
+            (PixelFormat48bpp6Channels, &[]),
+            (PixelFormat56bpp7Channels, &[]),
+            (PixelFormat64bpp8Channels, &[]),
+            (PixelFormat48bpp3Channels, &[]),
+            (PixelFormat64bpp4Channels, &[]),
+            (PixelFormat80bpp5Channels, &[]),
+            (PixelFormat96bpp6Channels, &[]),
+            (PixelFormat112bpp7Channels, &[]),
+            (PixelFormat128bpp8Channels, &[]),
+            (PixelFormat32bpp3ChannelsAlpha, &[]),
+            (PixelFormat40bpp4ChannelsAlpha, &[]),
+            (PixelFormat48bpp5ChannelsAlpha, &[]),
+            (PixelFormat56bpp6ChannelsAlpha, &[]),
+            (PixelFormat64bpp7ChannelsAlpha, &[]),
+            (PixelFormat72bpp8ChannelsAlpha, &[]),
+            (PixelFormat64bpp3ChannelsAlpha, &[]),
+            (PixelFormat80bpp4ChannelsAlpha, &[]),
+            (PixelFormat96bpp5ChannelsAlpha, &[]),
+            (PixelFormat112bpp6ChannelsAlpha, &[]),
+            (PixelFormat128bpp7ChannelsAlpha, &[]),
+            (PixelFormat144bpp8ChannelsAlpha, &[]),
+        ];
+        let (width, height) = (40, 20);
+        for &(format, mask) in formats {
+            let (stride, rows) = PixelInfo::from_format(format).buffer_rows(width as usize, height as usize);
+            let bytes_per_pixel = mask.len().max(1);
+            let pixels: Vec<u8> = noise_pixels(stride * rows).iter().enumerate().map(|(i, byte)| {
+                byte & mask.get(i % stride % bytes_per_pixel).unwrap_or(&0xff)
+            }).collect();
+            let bytes = encode(format, width, height, &pixels, &EncoderOptions::lossless()).unwrap();
+
+            let mut decoder = ImageDecode::with_reader(Cursor::new(bytes)).unwrap();
+            assert_eq!(decoder.get_pixel_format().unwrap(), format);
+            assert!(decode_all(&mut decoder) == pixels, "{:?} is not lossless", format);
+        }
+
+        for format in [PixelFormat128bppRGBAFloat, PixelFormat32bppRGBE, PixelFormat16bppGrayHalf, PixelFormat64bppRGBAHalf] {
+            let pixels = test_pixels(stride(format, width) * height as usize);
+            assert!(matches!(encode(format, width, height, &pixels, &EncoderOptions::lossless()),
+                Err(JXRError::LosslessNotSupported)), "{:?} should be rejected", format);
+        }
+        let pixels = test_pixels(stride(PixelFormat24bppRGB, width) * height as usize);
+        let lossy = EncoderOptions {
+            qp_v_hp: 20,
+            ..EncoderOptions::lossless()
+        };
+        assert!(matches!(encode_rgb(width, height, &pixels, &lossy),
+            Err(JXRError::LosslessWithLossyOptions)));
+    }
+
+    #[test]
+    fn decode_alpha() {
+        // Alpha comes back as stored, not filled in as opaque.
+        let (width, height) = (40, 20);
+        let pixels = test_pixels(width as usize * height as usize * 4);
+        let bytes = encode(PixelFormat32bppRGBA, width, height, &pixels, &EncoderOptions::lossless()).unwrap();
+        let alpha = |bytes: &[u8]| bytes.chunks(4).map(|pixel| pixel[3]).collect::<Vec<u8>>();
+        assert_eq!(alpha(&decode(bytes)), alpha(&pixels));
+    }
+
+    #[test]
+    fn encode_banded() {
+        let (width, height) = (40, 37);
+        let stride = width as usize * 3;
+        let pixels = test_pixels(stride * height as usize);
+
+        let mut banded = new_encoder(PixelFormat24bppRGB, width, height).into_banded().unwrap();
+        for band in pixels.chunks(stride * 5) {
+            banded.write_rows(band, stride, band.len() / stride).unwrap();
+        }
+        assert_eq!(decode(banded.finish().unwrap().into_inner()), pixels);
+
+        let mut banded = new_encoder(PixelFormat24bppRGB, width, height).into_banded().unwrap();
+        assert!(matches!(banded.write_rows(&pixels, stride - 1, 20), Err(JXRError::BufferTooSmall)));
+        banded.write_rows(&pixels, stride, 20).unwrap();
+        assert!(matches!(banded.finish(), Err(JXRError::RowCountMismatch)));
+
+        // A frame written whole can't be written again in bands.
+        let mut encoder = new_encoder(PixelFormat24bppRGB, width, height);
+        encoder.write_pixels(&pixels, stride).unwrap();
+        assert!(matches!(encoder.into_banded(), Err(JXRError::OutOfSequence)));
+    }
+
+    #[test]
+    fn encode_banded_alpha() {
+        let (width, height) = (40, 37);
+        let stride = width as usize * 4;
+        let pixels = test_pixels(stride * height as usize);
+
+        // Once with the alpha plane in memory, once spilled to a file.
+        for memory_limit in [DEFAULT_MEMORY_LIMIT, 0] {
+            let mut encoder = new_encoder(PixelFormat32bppRGBA, width, height);
+            encoder.set_options(&EncoderOptions::lossless());
+            encoder.set_memory_limit(memory_limit);
+            let mut banded = encoder.into_banded().unwrap();
+            for band in pixels.chunks(stride * 7) {
+                banded.write_rows(band, stride, band.len() / stride).unwrap();
+            }
+            assert_eq!(decode(banded.finish().unwrap().into_inner()), pixels);
+        }
+
+        let options = EncoderOptions {
+            alpha: AlphaMode::Discard,
+            ..EncoderOptions::lossless()
+        };
+        let mut encoder = new_encoder(PixelFormat32bppRGBA, width, height);
+        encoder.set_options(&options);
+        assert!(matches!(encoder.into_banded(), Err(JXRError::LosslessWithLossyOptions)));
+    }
+
+    #[test]
+    fn encode_tiled() {
+        let (width, height) = (100, 70);
+        let stride = width as usize * 3;
+        let pixels = test_pixels(stride * height as usize);
+
+        let layouts = [
+            TileLayout::uniform(32, 48),
+            TileLayout::new().column_boundaries(&[1, 4]).row_boundaries(&[2]).hard_boundaries(true),
+        ];
+        for tiles in &layouts {
+            let mut encoder = new_encoder(PixelFormat24bppRGB, width, height);
+            encoder.set_tile_layout(tiles);
+            encoder.write_pixels(&pixels, stride).unwrap();
+            assert_eq!(decode(encoder.into_writer().into_inner()), pixels);
+        }
+
+        let invalid = [
+            (TileLayout::uniform(20, 16), width, JXRError::InvalidTileLayout),
+            (TileLayout::new().column_boundaries(&[3, 2]), width, JXRError::InvalidTileLayout),
+            (TileLayout::new().column_boundaries(&[7]), width, JXRError::InvalidTileLayout),
+            (TileLayout::uniform(16, 16), 16 * 4097, JXRError::TooManyTiles),
+        ];
+        for (tiles, width, error) in &invalid {
+            let mut encoder = new_encoder(PixelFormat24bppRGB, *width, 16);
+            encoder.set_tile_layout(tiles);
+            let result = encoder.into_banded();
+            assert_eq!(result.err().map(|e| e.to_string()), Some(error.to_string()));
+        }
+    }
+
+    #[test]
+    fn encode_frequency_order() {
+        let (width, height) = (40, 37);
+        let pixels = test_pixels(width as usize * height as usize * 3);
+
+        for bitstream in [BitstreamFormat::Spatial, BitstreamFormat::Frequency] {
+            let options = EncoderOptions {
+                bitstream,
+                ..Default::default()
+            };
+            let bytes = encode_rgb(width, height, &pixels, &options).unwrap();
+
+            let mut decoder = ImageDecode::with_reader(Cursor::new(bytes)).unwrap();
+            assert_eq!(decoder.get_bitstream_format().unwrap(), bitstream);
+            assert_eq!(decode_all(&mut decoder), pixels);
+        }
+    }
+
+    #[test]
+    fn encode_overlap() {
+        let (width, height) = (40, 37);
+        let pixels = test_pixels(width as usize * height as usize * 3);
+
+        let cases = [
+            (EncoderOptions::default(), Overlap::One),
+            (EncoderOptions::with_quality(0.3), Overlap::Two),
+            (EncoderOptions { overlap: Some(Overlap::None), ..EncoderOptions::lossless() }, Overlap::None),
+            (EncoderOptions { overlap: Some(Overlap::Two), ..EncoderOptions::lossless() }, Overlap::Two),
+        ];
+        for (options, overlap) in &cases {
+            let bytes = encode_rgb(width, height, &pixels, options).unwrap();
+            let mut decoder = ImageDecode::with_reader(Cursor::new(bytes)).unwrap();
+            assert_eq!(decoder.get_overlap().unwrap(), *overlap);
+            if options.lossless {
+                assert_eq!(decode_all(&mut decoder), pixels);
+            }
+        }
+    }
+
+    #[test]
+    fn encode_chroma_subsampling() {
+        let (width, height) = (64, 48);
+        let pixels = test_pixels(width as usize * height as usize * 8);
+
+        let with_chroma = |chroma| EncoderOptions {
+            chroma: Some(chroma),
+            ..EncoderOptions::with_quality(0.9)
+        };
+        let full = encode_rgb(width, height, &pixels, &with_chroma(ChromaSubsampling::YUV444)).unwrap();
+        let half = encode_rgb(width, height, &pixels, &with_chroma(ChromaSubsampling::YUV422)).unwrap();
+        let quarter = encode_rgb(width, height, &pixels, &with_chroma(ChromaSubsampling::YUV420)).unwrap();
+        assert!(half.len() < full.len());
+        assert!(quarter.len() < half.len());
+        encode(PixelFormat32bppRGBA, width, height, &pixels, &with_chroma(ChromaSubsampling::YUV420)).unwrap();
+
+        for format in [PixelFormat64bppCMYK, PixelFormat24bpp3Channels, PixelFormat32bppRGBE, PixelFormat64bppRGBHalf] {
+            assert!(matches!(encode(format, width, height, &pixels, &with_chroma(ChromaSubsampling::YUV422)),
+                Err(JXRError::ChromaSubsamplingNotSupported)));
+        }
+        let lossless = EncoderOptions {
+            chroma: Some(ChromaSubsampling::YUV420),
+            ..EncoderOptions::lossless()
+        };
+        assert!(matches!(encode_rgb(width, height, &pixels, &lossless),
+            Err(JXRError::LosslessWithLossyOptions)));
+    }
+
+    #[test]
+    fn encode_to_target() {
+        let (width, height) = (64, 48);
+        let stride = width as usize * 3;
+        let pixels = test_pixels(stride * height as usize);
+        let lossless = encode_rgb(width, height, &pixels, &EncoderOptions::default()).unwrap();
+
+        // The header counts towards the size too.
+        let icc = vec![0x5a; 200];
+        let title = DescriptiveMetadata {
+            image_description: Some("rate controlled".to_string()),
+            ..Default::default()
+        };
+        let targets = [
+            (PixelFormat24bppRGB, RateTarget::Bytes(lossless.len() / 3)),
+            (PixelFormat24bppRGB, RateTarget::BitsPerPixel(2.0)),
+            (PixelFormat12bppYCC420, RateTarget::BitsPerPixel(2.0)),
+        ];
+        for (format, target) in targets {
+            // Subsampled rows hold two lines of pixels.
+            let (stride, rows) = PixelInfo::from_format(format).buffer_rows(width as usize, height as usize);
+            let mut encoder = new_encoder(format, width, height);
+            encoder.set_resolution(300.0, 300.0).unwrap();
+            encoder.set_icc_profile(&icc).unwrap();
+            encoder.set_descriptive_metadata(&title).unwrap();
+            let result = encoder.write_pixels_to_target(&pixels[..stride * rows], stride, target, 0.1).unwrap();
+            assert_eq!(encoder.options.qp, EncoderOptions::default().qp);
+            let bytes = encoder.into_writer().into_inner();
+
+            let max_bytes = match target {
+                RateTarget::Bytes(bytes) => bytes,
+                RateTarget::BitsPerPixel(bpp) => (bpp * (width * height) as f32 / 8.0) as usize,
+            };
+            assert_eq!(bytes.len(), result.bytes);
+            assert!(bytes.len() <= max_bytes);
+            assert!(result.qp > 1);
+            assert!(result.attempts >= 1 && result.attempts <= 8);
+            ImageDecode::with_reader(Cursor::new(bytes)).unwrap();
+        }
+
+        let mut encoder = new_encoder(PixelFormat24bppRGB, width, height);
+        assert!(matches!(encoder.write_pixels_to_target(&pixels, stride, RateTarget::Bytes(10), 0.1),
+            Err(JXRError::TargetSizeUnreachable)));
+    }
+
+    #[test]
+    fn encode_float_precision() {
+        let (width, height) = (64usize, 16usize);
+        let values: Vec<f32> = (0..width * height * 4).map(|i| {
+            // Logarithmic ramp from 0.001 to 1000, with alpha at 1.
+            if i % 4 == 3 { 1.0 } else { 10f32.powf(-3.0 + 6.0 * (i / 4 % width) as f32 / width as f32) }
+        }).collect();
+        let pixels: Vec<u8> = values.iter().flat_map(|value| value.to_le_bytes()).collect();
+
+        let mut last_error = f32::MAX;
+        for (precision, max_error) in [(FloatPrecision::SCRGB_DISPLAY, 1e-3), (FloatPrecision::SCRGB_MASTER, 1e-5)] {
+            let options = EncoderOptions {
+                precision,
+                ..Default::default()
+            };
+            let bytes = encode(PixelFormat128bppRGBAFloat, width as i32, height as i32, &pixels, &options).unwrap();
+            let output = decode(bytes);
+
+            let error = output.chunks(4).zip(&values).map(|(bytes, value)| {
+                let decoded = f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+                ((decoded - value) / value).abs()
+            }).fold(0.0, f32::max);
+            assert!(error < max_error);
+            assert!(error < last_error);
+            last_error = error;
+        }
+
+        let invalid = [
+            (PixelFormat128bppRGBAFloat, FloatPrecision { mantissa_or_shift: 20, exp_bias: 0 }, JXRError::InvalidPrecision),
+            (PixelFormat128bppRGBAFloat, FloatPrecision { mantissa_or_shift: 10, exp_bias: -128 }, JXRError::InvalidPrecision),
+            (PixelFormat96bppRGBFixedPoint, FloatPrecision { mantissa_or_shift: 2, exp_bias: 0 }, JXRError::InvalidPrecision),
+            (PixelFormat96bppRGBFixedPoint, FloatPrecision::SCRGB_DISPLAY, JXRError::PrecisionNotSupported),
+            (PixelFormat64bppRGBAHalf, FloatPrecision::SCRGB_DISPLAY, JXRError::PrecisionNotSupported),
+        ];
+        for (format, precision, error) in &invalid {
+            let options = EncoderOptions {
+                precision: *precision,
+                ..Default::default()
+            };
+            let result = encode(*format, width as i32, height as i32, &pixels, &options);
+            assert_eq!(result.err().map(|e| e.to_string()), Some(error.to_string()));
+        }
+
+        let bytes = encode(PixelFormat48bppRGBHalf, width as i32, height as i32, &pixels, &Default::default()).unwrap();
+        let decoder = ImageDecode::with_reader(Cursor::new(bytes)).unwrap();
+        assert_eq!(decoder.get_pixel_format().unwrap(), PixelFormat48bppRGBHalf);
+    }
+
+    #[test]
+    fn encode_metadata() {
+        let (width, height) = (40, 20);
+        let pixels = test_pixels(width as usize * height as usize * 3);
+
+        // Directories with one inline SHORT entry, so they're stored unchanged.
+        let ifd = |tag: u16, value: u16| -> Vec<u8> {
+            [&1u16.to_le_bytes()[..], &tag.to_le_bytes(), &3u16.to_le_bytes(),
+                &1u32.to_le_bytes(), &value.to_le_bytes(), &[0, 0], &0u32.to_le_bytes()].concat()
+        };
+        let icc = b"fake ICC profile data".to_vec();
+        let xmp = b"<x:xmpmeta xmlns:x='adobe:ns:meta/'>fake XMP packet</x:xmpmeta>".to_vec();
+        let exif = ifd(0xa001, 1);
+        let gps = ifd(0x0000, 0x0202);
+        let iptc = b"fake IPTC record".to_vec();
+        let photoshop = b"8BIM fake resources".to_vec();
+
+        let mut encoder = new_encoder(PixelFormat24bppRGB, width, height);
+        encoder.set_icc_profile(&icc).unwrap();
+        encoder.set_xmp_metadata(&xmp).unwrap();
+        encoder.set_exif_metadata(&exif).unwrap();
+        encoder.set_gps_metadata(&gps).unwrap();
+        encoder.set_iptc_metadata(&iptc).unwrap();
+        encoder.set_photoshop_metadata(&photoshop).unwrap();
+        encoder.write_pixels(&pixels, width as usize * 3).unwrap();
+        assert!(matches!(encoder.set_icc_profile(&icc), Err(JXRError::OutOfSequence)));
+        let bytes = encoder.into_writer().into_inner();
+
+        let contains = |needle: &[u8]| bytes.windows(needle.len()).any(|window| window == needle);
+        for blob in [&icc, &exif, &gps, &iptc, &photoshop] {
+            assert!(contains(blob));
+        }
+        assert!(contains(b"fake XMP packet"));
+
+        assert_eq!(decode(bytes), pixels);
+    }
+
+    #[test]
+    fn encode_descriptive_metadata() {
+        let (width, height) = (40, 20);
+        let pixels = test_pixels(width as usize * height as usize * 3);
+
+        let captions = ["", "a", "ab", "Ünïcødé caption 🙂"];
+        for caption in captions {
+            let metadata = DescriptiveMetadata {
+                image_description: Some("A test image".to_string()),
+                camera_make: Some("Make".to_string()),
+                date_time: Some("2024:01:02 03:04:05".to_string()),
+                artist: Some("Someone".to_string()),
+                copyright: Some("(c) Someone".to_string()),
+                rating_stars: Some(4),
+                rating_value: Some(75),
+                caption: Some(caption.to_string()),
+                page_number: Some((2, 5)),
+                host_computer: Some("x".to_string()),
+                ..Default::default()
+            };
+            let mut encoder = new_encoder(PixelFormat24bppRGB, width, height);
+            encoder.set_descriptive_metadata(&metadata).unwrap();
+            encoder.write_pixels(&pixels, width as usize * 3).unwrap();
+            let bytes = encoder.into_writer().into_inner();
+
+            let decoder = ImageDecode::with_reader(Cursor::new(bytes)).unwrap();
+            assert_eq!(decoder.get_descriptive_metadata().unwrap(), metadata);
+        }
+
+        let mut encoder = ImageEncode::with_writer(Cursor::new(Vec::new())).unwrap();
+        let non_ascii = DescriptiveMetadata {
+            artist: Some("Zoë".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(encoder.set_descriptive_metadata(&non_ascii), Err(JXRError::NonAsciiMetadata)));
+        let nul = DescriptiveMetadata {
+            caption: Some("a\0b".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(encoder.set_descriptive_metadata(&nul), Err(JXRError::NulError(_))));
+    }
+
+    #[test]
+    fn encode_resolution() {
+        let (width, height) = (40, 20);
+        let pixels = test_pixels(width as usize * height as usize * 3);
+
+        let mut encoder = new_encoder(PixelFormat24bppRGB, width, height);
+        for (horiz, vert) in [(0.0, 300.0), (300.0, -1.0), (f32::NAN, 300.0), (300.0, f32::INFINITY)] {
+            assert!(matches!(encoder.set_resolution(horiz, vert), Err(JXRError::InvalidResolution)));
+        }
+        encoder.set_resolution(300.0, 150.5).unwrap();
+        encoder.write_pixels(&pixels, width as usize * 3).unwrap();
+        assert!(matches!(encoder.set_resolution(72.0, 72.0), Err(JXRError::OutOfSequence)));
+        let bytes = encoder.into_writer().into_inner();
+
+        let decoder = ImageDecode::with_reader(Cursor::new(bytes)).unwrap();
+        assert_eq!(decoder.get_resolution().unwrap(), (300.0, 150.5));
+    }
+
+    #[test]
+    fn encode_unseekable() {
+        // Only implements Write, like a pipe.
+        struct Pipe(Vec<u8>);
+        impl std::io::Write for Pipe {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.write(buf)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let (width, height) = (40, 20);
+        let pixels = test_pixels(width as usize * height as usize * 3);
+        let expected = encode_rgb(width, height, &pixels, &Default::default()).unwrap();
+
+        // Once in memory, once spilled to a file.
+        for memory_limit in [DEFAULT_MEMORY_LIMIT, 16] {
+            let spool = SpooledWriter::with_memory_limit(Pipe(Vec::new()), memory_limit);
+            let mut encoder = ImageEncode::with_writer(spool).unwrap();
+            encoder.set_pixel_format(PixelFormat24bppRGB).unwrap();
+            encoder.set_size(width, height).unwrap();
+            encoder.write_pixels(&pixels, width as usize * 3).unwrap();
+            let bytes = encoder.into_writer().finish().unwrap().0;
+            assert_eq!(bytes, expected);
+        }
+    }
+
+    #[test]
+    fn encode_subbands() {
+        let (width, height) = (64, 48);
+        let pixels = test_pixels(width as usize * height as usize * 3);
+
+        // Sizes of files that still decode.
+        let encode = |options: &EncoderOptions| {
+            let bytes = encode_rgb(width, height, &pixels, options)?;
+            assert_eq!(decode(bytes.clone()).len(), pixels.len());
+            Ok::<_, JXRError>(bytes.len())
+        };
+
+        // Each step throws away more, so should be smaller.
+        let mut last = usize::MAX;
+        for subband in [Subband::All, Subband::NoFlexbits, Subband::NoHighpass, Subband::DcOnly] {
+            let size = encode(&EncoderOptions {
+                subband,
+                ..Default::default()
+            }).unwrap();
+            assert!(size < last);
+            last = size;
+        }
+
+        let full = encode(&EncoderOptions::default()).unwrap();
+        let trimmed = encode(&EncoderOptions {
+            trim_flexbits: 1,
+            ..Default::default()
+        }).unwrap();
+        assert!(trimmed < full);
+
+        let too_many = EncoderOptions {
+            trim_flexbits: 16,
+            ..Default::default()
+        };
+        assert!(matches!(encode(&too_many), Err(JXRError::InvalidFlexbitTrim)));
+        let lossless = EncoderOptions {
+            subband: Subband::NoFlexbits,
+            ..EncoderOptions::lossless()
+        };
+        assert!(matches!(encode(&lossless), Err(JXRError::LosslessWithLossyOptions)));
+    }
+
+    #[test]
+    fn encode_alpha_quality() {
+        let (width, height) = (40, 20);
+        let stride = width as usize * 4;
+        let pixels = test_pixels(stride * height as usize);
+
+        // Lossless alpha over lossy color, both ways of storing alpha.
+        // Dropping subbands or flexbits doesn't reach planar alpha.
+        let cases = [
+            (AlphaMode::Planar, Subband::All, 0),
+            (AlphaMode::Interleaved, Subband::All, 0),
+            (AlphaMode::Planar, Subband::NoHighpass, 2),
+        ];
+        for (alpha, subband, trim_flexbits) in cases {
+            let options = EncoderOptions {
+                alpha,
+                subband,
+                trim_flexbits,
+                quality: Some(0.5),
+                ..Default::default()
+            };
+            let output = decode(encode(PixelFormat32bppRGBA, width, height, &pixels, &options).unwrap());
+
+            let alpha_plane = |bytes: &[u8]| bytes.chunks(4).map(|pixel| pixel[3]).collect::<Vec<u8>>();
+            let color_plane = |bytes: &[u8]| bytes.chunks(4).flat_map(|pixel| &pixel[..3]).copied().collect::<Vec<u8>>();
+            assert_eq!(alpha_plane(&output), alpha_plane(&pixels));
+            assert_ne!(color_plane(&output), color_plane(&pixels));
+        }
+
+        let lossy_alpha = EncoderOptions {
+            alpha_qp: 20,
+            ..Default::default()
+        };
+        assert!(matches!(encode_rgb(width, height, &pixels, &lossy_alpha), Err(JXRError::NoAlphaChannel)));
+        let lossless = EncoderOptions {
+            alpha_qp: 20,
+            ..EncoderOptions::lossless()
+        };
+        assert!(matches!(encode(PixelFormat32bppRGBA, width, height, &pixels, &lossless),
+            Err(JXRError::LosslessWithLossyOptions)));
+        let interleaved = EncoderOptions {
+            alpha: AlphaMode::Interleaved,
+            ..Default::default()
+        };
+        assert!(matches!(encode(PixelFormat32bpp3ChannelsAlpha, width, height, &pixels, &interleaved),
+            Err(JXRError::InterleavedAlphaNotSupported)));
+    }
+
+    #[test]
+    fn encode_converted() {
+        let (width, height) = (40, 20);
+        let stride = width as usize * 3;
+        let pixels = test_pixels(stride * height as usize);
+
+        // Channel order is swapped losslessly, once per frame.
+        let mut encoder = new_encoder(PixelFormat24bppRGB, width, height);
+        encoder.write_pixels_from(PixelFormat24bppBGR, &pixels, stride).unwrap();
+        assert!(matches!(encoder.write_pixels_from(PixelFormat24bppBGR, &pixels, stride), Err(JXRError::OutOfSequence)));
+        let swapped: Vec<u8> = pixels.chunks(3).flat_map(|pixel| [pixel[2], pixel[1], pixel[0]]).collect();
+        assert_eq!(decode(encoder.into_writer().into_inner()), swapped);
+
+        // Packed float RGB is padded out to four channels, giving
+        // the same file as encoding the padded pixels directly.
+        let floats: Vec<f32> = (0..width * height * 3).map(|i| (i % 97) as f32 / 32.0).collect();
+        let packed: Vec<u8> = floats.iter().flat_map(|f| f.to_ne_bytes()).collect();
+        let padded: Vec<u8> = floats.chunks(3).flat_map(|pixel| [pixel[0], pixel[1], pixel[2], 0.0])
+            .flat_map(|f| f.to_ne_bytes()).collect();
+        let direct = encode(PixelFormat128bppRGBFloat, width, height, &padded, &Default::default()).unwrap();
+        let mut encoder = new_encoder(PixelFormat128bppRGBFloat, width, height);
+        encoder.write_pixels_from(PixelFormat96bppRGBFloat, &packed, width as usize * 12).unwrap();
+        assert_eq!(encoder.into_writer().into_inner(), direct);
+
+        let mut encoder = new_encoder(PixelFormat128bppRGBFloat, width, height);
+        match encoder.write_pixels_from(PixelFormat8bppGray, &pixels, stride) {
+            Err(JXRError::NoConversion { possible, .. }) => {
+                assert_eq!(possible, [PixelFormat24bppRGB, PixelFormat24bppBGR]);
+            },
+            other => panic!("unexpected result {:?}", other)
+        }
+    }
+
+    #[test]
+    fn encode_bilevel() {
+        let (width, height) = (37, 20);
+        let stride = stride(PixelFormatBlackWhite, width);
+        let gray: Vec<u8> = (0..width * height).map(|i| if (i % width) * (i / width) % 7 < 3 { 0 } else { 255 }).collect();
+
+        for &polarity in &[Polarity::WhiteIsOne, Polarity::BlackIsOne] {
+            let mut packed = vec![0u8; stride * height as usize];
+            for (gray_row, row) in gray.chunks(width as usize).zip(packed.chunks_mut(stride)) {
+                polarity.pack_row(gray_row, row).unwrap();
+            }
+            let options = EncoderOptions {
+                polarity,
+                ..EncoderOptions::lossless()
+            };
+            let bytes = encode(PixelFormatBlackWhite, width, height, &packed, &options).unwrap();
+
+            let mut decoder = ImageDecode::with_reader(Cursor::new(bytes)).unwrap();
+            assert_eq!(decoder.get_polarity().unwrap(), polarity);
+            let output = decode_all(&mut decoder);
+            assert_eq!(output, packed);
+
+            let mut unpacked = vec![0u8; gray.len()];
+            for (row, gray_row) in output.chunks(stride).zip(unpacked.chunks_mut(width as usize)) {
+                polarity.unpack_row(row, gray_row).unwrap();
+            }
+            assert_eq!(unpacked, gray);
+        }
+
+        // Black is zero bits by default; ones the other way.
+        let mut row = [0u8; 2];
+        Polarity::WhiteIsOne.pack_row(&[0, 255, 0, 0, 0, 0, 0, 0, 200], &mut row).unwrap();
+        assert_eq!(row, [0x40, 0x80]);
+        Polarity::BlackIsOne.pack_row(&[0, 255, 0, 0, 0, 0, 0, 0, 200], &mut row).unwrap();
+        assert_eq!(row, [0xbf, 0x00]);
+
+        let options = EncoderOptions {
+            polarity: Polarity::BlackIsOne,
+            ..Default::default()
+        };
+        let pixels = test_pixels(width as usize * height as usize);
+        assert!(matches!(encode(PixelFormat8bppGray, width, height, &pixels, &options),
+            Err(JXRError::PolarityNotSupported)));
+    }
+
+    #[test]
+    fn encode_frames() {
+        let frames = [
+            (PixelFormat32bppRGBA, 40, 37),
+            (PixelFormat24bppRGB, 40, 20),
+            (PixelFormat8bppGray, 17, 9),
+        ];
+        let pages: Vec<Vec<u8>> = frames.iter().enumerate().map(|(i, &(format, width, height))| {
+            test_pixels(stride(format, width) * height as usize).iter().map(|b| b.wrapping_add(i as u8)).collect()
+        }).collect();
+
+        let mut encoder = ImageEncode::with_writer(Cursor::new(Vec::new())).unwrap();
+        assert!(matches!(encoder.new_frame(), Err(JXRError::OutOfSequence)));
+        encoder.set_options(&EncoderOptions::lossless());
+        for (i, (&(format, width, height), pixels)) in frames.iter().zip(&pages).enumerate() {
+            if i > 0 {
+                encoder.new_frame().unwrap();
+            }
+            encoder.set_pixel_format(format).unwrap();
+            encoder.set_size(width, height).unwrap();
+            encoder.set_descriptive_metadata(&DescriptiveMetadata {
+                page_number: Some((i as u16, 3)),
+                ..Default::default()
+            }).unwrap();
+            let stride = stride(format, width);
+            if i == 0 {
+                // Banded, with planar alpha copied in after the image.
+                let mut banded = encoder.into_banded().unwrap();
+                banded.write_rows(pixels, stride, height as usize).unwrap();
+                encoder = banded.finish_frame().unwrap();
+                // The frame is done, and its alpha temp stream gone.
+                assert!(unsafe { (*encoder.raw).WMP.pPATempFile }.is_null());
+                assert!(matches!(encoder.write_pixels(pixels, stride), Err(JXRError::OutOfSequence)));
+            } else {
+                encoder.write_pixels(pixels, stride).unwrap();
+            }
+        }
+        let bytes = encoder.into_writer().into_inner();
+
+        let mut decoder = ImageDecode::with_reader(Cursor::new(bytes)).unwrap();
+        assert_eq!(decoder.frame_count().unwrap(), 3);
+        // Going back to the first frame starts decoding over.
+        for &frame in &[0, 1, 2, 0] {
+            decoder.select_frame(frame).unwrap();
+            let (format, width, height) = frames[frame as usize];
+            assert_eq!(decoder.get_pixel_format().unwrap(), format);
+            assert_eq!(decoder.get_size().unwrap(), (width, height));
+            assert_eq!(decoder.get_descriptive_metadata().unwrap().page_number, Some((frame as u16, 3)));
+            assert_eq!(decode_all(&mut decoder), pages[frame as usize]);
+        }
+        assert!(matches!(decoder.select_frame(3), Err(JXRError::IndexNotFound)));
+
+        let (width, height) = (40, 20);
+        let bytes = encode_rgb(width, height, &test_pixels(width as usize * height as usize * 3), &Default::default()).unwrap();
+        let decoder = ImageDecode::with_reader(Cursor::new(bytes)).unwrap();
+        assert_eq!(decoder.frame_count().unwrap(), 1);
+    }
+
+    #[test]
+    fn decode_scaled() {
+        // A smooth gradient, so every scale should land close to
+        // the pixel at the centre of each block.
+        let (width, height) = (200usize, 130usize);
+        let pixels: Vec<u8> = (0..width * height * 4).map(|i| {
+            let (x, y) = ((i / 4) % width, (i / 4) / width);
+            [x as u8, y as u8, ((x + y) / 2) as u8, 255 - x as u8][i % 4]
+        }).collect();
+        let bytes = encode(PixelFormat32bppRGBA, width as i32, height as i32, &pixels, &EncoderOptions::lossless()).unwrap();
+
+        let mut decoder = ImageDecode::with_reader(Cursor::new(bytes)).unwrap();
+        assert!(matches!(decoder.get_scaled_size(3), Err(JXRError::InvalidScale)));
+        for &scale in &[2usize, 4, 8, 16, 1] {
+            let (w, h) = decoder.get_scaled_size(scale as u32).unwrap();
+            assert_eq!((w, h), (width.div_ceil(scale) as i32, height.div_ceil(scale) as i32));
+            let (w, h) = (w as usize, h as usize);
+            let mut output = vec![0u8; w * h * 4];
+            decoder.copy_scaled(&Rect::new(0, 0, w as i32, h as i32), scale as u32, &mut output, w * 4).unwrap();
+            for y in 0..h - 1 {
+                for x in 0..w - 1 {
+                    let source = &pixels[((y * scale + scale / 2) * width + x * scale + scale / 2) * 4..][..4];
+                    for (a, b) in output[(y * w + x) * 4..][..4].iter().zip(source) {
+                        assert!((*a as i32 - *b as i32).abs() <= 4);
+                    }
+                }
+            }
+
+            // A region gives the same pixels as the whole.
+            let mut region = vec![0u8; 5 * 3 * 4];
+            decoder.copy_scaled(&Rect::new(w as i32 / 2, h as i32 / 3, 5, 3), scale as u32, &mut region, 5 * 4).unwrap();
+            for row in 0..3 {
+                assert_eq!(region[row * 20..][..20], output[((h / 3 + row) * w + w / 2) * 4..][..20]);
+            }
+
+            // Full size decoding is unaffected.
+            assert_eq!(decode_all(&mut decoder), pixels);
+        }
+        assert!(matches!(decoder.copy_scaled(&Rect::new(0, 0, 14, 9), 16, &mut [0u8; 14 * 9 * 4], 14 * 4),
+            Err(JXRError::InvalidArgument)));
+        assert!(matches!(decoder.copy_scaled(&Rect::new(i32::MAX, 0, 1, 1), 2, &mut [0u8; 4], 4),
+            Err(JXRError::InvalidArgument)));
+        assert!(matches!(decoder.copy_scaled(&Rect::new(0, 1, 1, i32::MAX), 2, &mut [0u8; 4], 4),
+            Err(JXRError::InvalidArgument)));
+    }
+
+    #[test]
+    fn decode_subbands() {
+        // A gradient under fine and 4x4 checkerboards, so each band matters.
+        let (width, height) = (96usize, 64usize);
+        let pixels: Vec<u8> = (0..width * height * 3).map(|i| {
+            let (x, y) = ((i / 3) % width, (i / 3) / width);
+            let fine = if (x ^ y) & 1 == 1 { 8 } else { -8 };
+            let coarse = if ((x / 4) ^ (y / 4)) & 1 == 1 { 16 } else { -16 };
+            ((x + y) as i32 + fine + coarse + 50) as u8
+        }).collect();
+        let error = |output: &[u8]| -> f64 {
+            output.iter().zip(&pixels).map(|(a, b)| (*a as i32 - *b as i32).abs() as f64).sum::<f64>() / pixels.len() as f64
+        };
+
+        let options = EncoderOptions {
+            bitstream: BitstreamFormat::Frequency,
+            ..EncoderOptions::lossless()
+        };
+        let bytes = encode_rgb(width as i32, height as i32, &pixels, &options).unwrap();
+        let mut decoder = ImageDecode::with_reader(Cursor::new(bytes)).unwrap();
+        let mut last = -1.0;
+        for &subband in &[Subband::All, Subband::NoFlexbits, Subband::NoHighpass, Subband::DcOnly] {
+            decoder.set_options(&DecodeOptions { subband, ..Default::default() }).unwrap();
+            let error = error(&decode_all(&mut decoder));
+            assert!(error > last && error < 32.0);
+            last = error;
+        }
+        decoder.set_options(&Default::default()).unwrap();
+        assert_eq!(decode_all(&mut decoder), pixels);
+
+        // Spatial files can't skip what they hold, only what they lack.
+        let options = EncoderOptions {
+            subband: Subband::NoHighpass,
+            ..Default::default()
+        };
+        let bytes = encode_rgb(width as i32, height as i32, &pixels, &options).unwrap();
+        let mut decoder = ImageDecode::with_reader(Cursor::new(bytes)).unwrap();
+        decoder.set_options(&DecodeOptions { subband: Subband::NoHighpass, ..Default::default() }).unwrap();
+        assert!(matches!(decoder.set_options(&DecodeOptions { subband: Subband::DcOnly, ..Default::default() }),
+            Err(JXRError::SubbandNeedsFrequencyOrder)));
+    }
+
+    #[test]
+    fn decode_deblocking() {
+        // Smooth waves, quantized hard enough to show 4x4 blocks.
+        let (width, height) = (128, 96);
+        let pixels: Vec<u8> = (0..width * height).map(|i| {
+            let (x, y) = ((i % width) as f64, (i / width) as f64);
+            (128.0 + 60.0 * ((x / 20.0).sin() + (y / 17.0 + x / 66.0).cos())) as u8
+        }).collect();
+        let options = EncoderOptions {
+            qp: 120,
+            overlap: Some(Overlap::None),
+            ..Default::default()
+        };
+        let bytes = encode(PixelFormat8bppGray, width as i32, height as i32, &pixels, &options).unwrap();
+        let mut decoder = ImageDecode::with_reader(Cursor::new(bytes)).unwrap();
+
+        // Mean step across block edges, and mean error.
+        let mut decode = |deblocking| {
+            decoder.set_options(&DecodeOptions { deblocking, ..Default::default() }).unwrap();
+            let output = decode_all(&mut decoder);
+            let edges = (0..pixels.len()).filter(|i| i % width % 4 == 0 && i % width > 0);
+            let step = edges.map(|i| (output[i] as i32 - output[i - 1] as i32).abs()).sum::<i32>() as f64;
+            let error = output.iter().zip(&pixels).map(|(a, b)| (*a as i32 - *b as i32).abs()).sum::<i32>() as f64;
+            (step, error)
+        };
+        let (step, error) = decode(0);
+        let (smoothed_step, smoothed_error) = decode(2);
+        assert!(smoothed_step < step / 2.0);
+        assert!(smoothed_error < error);
+
+        assert!(matches!(decoder.set_options(&DecodeOptions { deblocking: 5, ..Default::default() }),
+            Err(JXRError::InvalidDeblocking)));
+    }
+
+    #[test]
+    fn decode_orientation() {
+        // Every pixel holds its own stored position.
+        let (width, height) = (40usize, 24usize);
+        let stored = |x: usize, y: usize| (y * 256 + x) as u16;
+        let pixels: Vec<u8> = (0..width * height).flat_map(|i| stored(i % width, i / width).to_ne_bytes()).collect();
+        let orientations = [
+            Orientation::None, Orientation::FlipV, Orientation::FlipH, Orientation::FlipVH,
+            Orientation::Rotate90, Orientation::Rotate90FlipV, Orientation::Rotate90FlipH, Orientation::Rotate90FlipVH,
+        ];
+        for &orientation in &orientations {
+            let options = EncoderOptions { orientation, ..EncoderOptions::lossless() };
+            let bytes = encode(PixelFormat16bppGray, width as i32, height as i32, &pixels, &options).unwrap();
+
+            let mut decoder = ImageDecode::with_reader(Cursor::new(bytes)).unwrap();
+            assert_eq!(decoder.orientation().unwrap(), orientation);
+            let (w, h) = if orientation.is_rotated() { (height, width) } else { (width, height) };
+            assert_eq!(decoder.get_size().unwrap(), (w as i32, h as i32));
+            assert_eq!(decoder.get_scaled_size(1).unwrap(), (w as i32, h as i32));
+
+            // Undo the flips, then the turn.
+            let flip_h = matches!(orientation, Orientation::FlipH | Orientation::FlipVH | Orientation::Rotate90FlipH | Orientation::Rotate90FlipVH);
+            let flip_v = matches!(orientation, Orientation::FlipV | Orientation::FlipVH | Orientation::Rotate90FlipV | Orientation::Rotate90FlipVH);
+            let expected = |x: usize, y: usize| {
+                let x = if flip_h { w - 1 - x } else { x };
+                let y = if flip_v { h - 1 - y } else { y };
+                let (x, y) = if orientation.is_rotated() { (y, height - 1 - x) } else { (x, y) };
+                stored(x, y)
+            };
+            let mut output = decode_all(&mut decoder);
+            for y in 0..h {
+                for x in 0..w {
+                    let i = (y * w + x) * 2;
+                    assert_eq!(u16::from_ne_bytes([output[i], output[i + 1]]), expected(x, y), "{:?} at {},{}", orientation, x, y);
+                }
+            }
+
+            // Regions are in turned pixels, and need the whole
+            // image once rotated.
+            let mut region = vec![0u8; 5 * 3 * 2];
+            let result = decoder.copy_scaled(&Rect::new(7, 4, 5, 3), 1, &mut region, 5 * 2);
+            if orientation.is_rotated() {
+                assert!(matches!(result, Err(JXRError::InvalidArgument)));
+                assert!(matches!(decoder.copy(&Rect::new(0, 0, 16, 16), &mut output, w * 2), Err(JXRError::InvalidArgument)));
+            } else {
+                result.unwrap();
+                for row in 0..3 {
+                    assert_eq!(region[row * 10..][..10], output[((4 + row) * w + 7) * 2..][..10]);
+                }
+            }
+
+            // Or the stored pixels as they are.
+            decoder.set_options(&DecodeOptions { apply_orientation: false, ..Default::default() }).unwrap();
+            assert_eq!(decoder.get_size().unwrap(), (width as i32, height as i32));
+            assert_eq!(decode_all(&mut decoder), pixels);
+        }
+    }
+
+    #[test]
+    fn decode_rows() {
+        let (width, height) = (50usize, 70usize);
+        let pixels: Vec<u8> = (0..width * height * 4).map(|i| {
+            let (x, y) = ((i / 4) % width, (i / 4) / width);
+            [x as u8, y as u8, (x * y) as u8, (x + y) as u8][i % 4]
+        }).collect();
+        for &orientation in &[Orientation::None, Orientation::FlipVH] {
+            let options = EncoderOptions { orientation, ..EncoderOptions::lossless() };
+            let bytes = encode(PixelFormat32bppRGBA, width as i32, height as i32, &pixels, &options).unwrap();
+
+            let mut decoder = ImageDecode::with_reader(Cursor::new(bytes)).unwrap();
+            let expected = decode_all(&mut decoder);
+            for &band_height in &[1u32, 7, 16, 100] {
+                let mut output = vec![0u8; pixels.len()];
+                let mut count = 0;
+                for band in decoder.rows(band_height).unwrap() {
+                    let band = band.unwrap();
+                    assert_eq!(band.stride, width * 4);
+                    assert_eq!(band.pixels.len(), band.stride * band.height as usize);
+                    output[band.y as usize * width * 4..][..band.pixels.len()].copy_from_slice(&band.pixels);
+                    count += 1;
+                }
+                assert_eq!(count, height.div_ceil(band_height as usize));
+                assert_eq!(output, expected);
+            }
+
+            // Stopping early leaves the decoder usable.
+            let first = decoder.rows(20).unwrap().next().unwrap().unwrap();
+            assert_eq!(first.height, 20);
+            assert_eq!(decode_all(&mut decoder), expected);
+            assert!(matches!(decoder.rows(0), Err(JXRError::InvalidArgument)));
+        }
     }
 }